@@ -1,24 +1,62 @@
 use std::error::Error;
+use std::path::PathBuf;
 
 use opaquekeys::CourseKey;
 
 use completion::{App, User};
 use completion::adapters::{db, rest};
+use completion::adapters::xblock::FileXBlockModeAdapter;
+use completion::aggregator::{AggregationConfig, AggregationWarning};
+use completion::ports::blockcompletions::BlockCompletionService;
+use completion::ports::course::CourseService;
+use completion::ports::enrollment::EnrollmentService;
+use completion::ports::xblock::XBlockModeService;
+use completion::validation::{validate_structure, validate_structure_with_service};
 
 use structopt::StructOpt;
 
 #[derive(Debug, StructOpt)]
 #[structopt(name = "rust-completion-experiment")]
 
-struct CliOptions {
-    #[structopt(parse(try_from_str))]
-    user: User,
-    #[structopt(parse(try_from_str))]
-    course_key: CourseKey,
+enum CliOptions {
+    /// Show a user's completion in a course.
+    #[structopt(name = "completion")]
+    Completion {
+        #[structopt(parse(try_from_str))]
+        user: User,
+        #[structopt(parse(try_from_str))]
+        course_key: CourseKey,
+        /// A TOML or JSON file of extra xblock modes.
+        #[structopt(long = "xblock-modes", parse(from_os_str))]
+        xblock_modes: Option<PathBuf>,
+    },
+    /// Report problems with a course's structure.
+    #[structopt(name = "validate")]
+    Validate {
+        #[structopt(parse(try_from_str))]
+        course_key: CourseKey,
+        /// A TOML or JSON file of extra xblock modes.
+        #[structopt(long = "xblock-modes", parse(from_os_str))]
+        xblock_modes: Option<PathBuf>,
+    },
 }
 
 fn main() -> Result<(), Box<Error>> {
-    let CliOptions { user, course_key } = CliOptions::from_args();
+    match CliOptions::from_args() {
+        CliOptions::Completion {
+            user,
+            course_key,
+            xblock_modes,
+        } => completion(user, course_key, xblock_modes),
+        CliOptions::Validate {
+            course_key,
+            xblock_modes,
+        } => validate(course_key, xblock_modes),
+    }
+    Ok(())
+}
+
+fn completion(user: User, course_key: CourseKey, xblock_modes: Option<PathBuf>) {
     dbg!(&course_key);
     let conn = db::edxapp_connect().expect("mysql connect");
     let blockcompletion_service = {
@@ -32,15 +70,104 @@ fn main() -> Result<(), Box<Error>> {
     let course_service = rest::CourseAdapter::new();
 
     let app = App::new(blockcompletion_service, course_service, enrollment_service);
-    let result = app.get_user_completion(&user, &course_key).unwrap();
-    for agg in result {
-        println!(
-            "{}: {}/{} ({:.2}%)",
-            agg.block_key,
-            agg.earned,
-            agg.possible,
-            agg.percent() * 100.0
-        );
+    match xblock_modes {
+        Some(path) => print_completion(
+            app.with_xblock_mode_service(FileXBlockModeAdapter::new(path)),
+            &user,
+            &course_key,
+        ),
+        None => print_completion(app, &user, &course_key),
     }
-    Ok(())
+}
+
+fn print_completion<B, C, E, X>(app: App<B, C, E, X>, user: &User, course_key: &CourseKey)
+where
+    B: BlockCompletionService,
+    C: CourseService,
+    E: EnrollmentService,
+    X: XBlockModeService,
+{
+    let result = match app.get_user_completion_with_warnings(user, course_key) {
+        Ok(result) => result,
+        Err(err) => {
+            eprintln!("Could not fetch user completions: {}", error_chain(&err));
+            std::process::exit(1);
+        }
+    };
+    for warning in result.warnings {
+        match warning {
+            AggregationWarning::UnknownBlockType { blocktype } => {
+                eprintln!("Warning: unknown blocktype {}", blocktype)
+            }
+        }
+    }
+    for agg in result.aggregators {
+        match agg.percent() {
+            Some(percent) => println!(
+                "{}: {}/{} ({:.2}%)",
+                agg.block_key,
+                agg.earned,
+                agg.possible,
+                percent * 100.0
+            ),
+            None => println!("{}: nothing to complete", agg.block_key),
+        }
+    }
+}
+
+fn validate(course_key: CourseKey, xblock_modes: Option<PathBuf>) {
+    // Validation only needs the course structure, so skip the database.
+    let course_service = rest::CourseAdapter::new();
+    let structure = match course_service.get_course(&course_key) {
+        Ok(structure) => structure,
+        Err(err) => {
+            eprintln!("Could not fetch course structure: {}", error_chain(&err));
+            std::process::exit(1);
+        }
+    };
+    let config = AggregationConfig::default();
+    let report = match xblock_modes {
+        Some(path) => {
+            match validate_structure_with_service(&structure, &config, &FileXBlockModeAdapter::new(path)) {
+                Ok(report) => report,
+                Err(err) => {
+                    eprintln!("Could not load xblock modes: {}", error_chain(&err));
+                    std::process::exit(1);
+                }
+            }
+        }
+        None => validate_structure(&structure, &config),
+    };
+    match report.root {
+        Some(ref root) => println!("Root: {}", root),
+        None => println!("Root: none"),
+    }
+    println!("Maximum depth: {}", report.max_depth);
+    if let Some(ref err) = report.error {
+        println!("Error: {}", err);
+    }
+    for block in &report.unreachable_blocks {
+        println!("Unreachable block: {}", block);
+    }
+    for blocktype in &report.unknown_blocktypes {
+        println!("Unknown blocktype: {}", blocktype);
+    }
+    for block in &report.empty_aggregators {
+        println!("Nothing to complete in: {}", block);
+    }
+    if !report.is_clean() {
+        std::process::exit(1);
+    }
+}
+
+/// An error's message followed by the messages of its sources.
+fn error_chain(err: &dyn Error) -> String {
+    let mut message = err.to_string();
+    let mut source = err.source();
+    while let Some(err) = source {
+        message.push_str(": ");
+        message.push_str(&err.to_string());
+        source = err.source();
+    }
+    message
 }
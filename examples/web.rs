@@ -3,18 +3,48 @@
 use std::path::PathBuf;
 
 use rocket;
+use rocket::http::Status;
+use rocket::response::status;
 use rocket_contrib::json::Json;
 
-use completion::{App, User};
+use completion::{App, AppError, User};
 use completion::adapters::{db, rest};
+use completion::ports::ServiceError;
+use opaquekeys::CourseKey;
 
-#[rocket::get("/<username>/<coursekey..>")]
-fn index(username: String, coursekey: PathBuf) -> Json<serde_json::Value>
-{
+fn error_response(err: AppError) -> status::Custom<String> {
+    let status = match &err {
+        AppError::NotEnrolled => Status::Forbidden,
+        AppError::CourseNotFound | AppError::BlockNotCompletable(_) => Status::NotFound,
+        AppError::InvalidCompletion(_) => Status::BadRequest,
+        AppError::Structure(_) | AppError::XBlockModes(_) => Status::InternalServerError,
+        AppError::Enrollment(err) | AppError::Course(err) | AppError::BlockCompletion(err) => {
+            match err {
+                ServiceError::NotFound => Status::NotFound,
+                ServiceError::Timeout(_) => Status::GatewayTimeout,
+                ServiceError::Unavailable(_) | ServiceError::RateLimited { .. } => {
+                    Status::ServiceUnavailable
+                }
+                // Our credentials for the backend are wrong, which is our
+                // problem rather than the backend's.
+                ServiceError::Authentication(_) => Status::InternalServerError,
+                _ => Status::BadGateway,
+            }
+        }
+    };
+    status::Custom(status, err.to_string())
+}
 
-    let user = User { username };
+fn parse_coursekey(coursekey: PathBuf) -> Result<CourseKey, status::Custom<String>> {
     let coursekey = coursekey.to_string_lossy();
-    let coursekey = coursekey.parse().unwrap();
+    coursekey.parse().map_err(|_| {
+        status::Custom(Status::BadRequest, format!("Invalid course key: {}", coursekey))
+    })
+}
+
+type WebApp = App<db::MySqlBlockCompletionAdapter, rest::CourseAdapter, db::MySqlEnrollmentAdapter>;
+
+fn build_app() -> WebApp {
     let conn = db::edxapp_connect().expect("mysql connect");
     let blockcompletion_service = {
         let conn = conn.clone();
@@ -26,11 +56,39 @@ fn index(username: String, coursekey: PathBuf) -> Json<serde_json::Value>
     };
     let course_service = rest::CourseAdapter::new();
 
-    let app = App::new(blockcompletion_service, course_service, enrollment_service);
-    let result = app.get_user_completion(&user, &coursekey).expect("Could not fetch user completions");
-    Json(serde_json::to_value(result).unwrap())
+    App::new(blockcompletion_service, course_service, enrollment_service)
+}
+
+#[rocket::get("/<username>/<coursekey..>", rank = 2)]
+fn index(
+    username: String,
+    coursekey: PathBuf,
+) -> Result<Json<serde_json::Value>, status::Custom<String>> {
+
+    let user = User { username };
+    let coursekey = parse_coursekey(coursekey)?;
+    let app = build_app();
+    let result = app.get_user_completion(&user, &coursekey).map_err(error_response)?;
+    Ok(Json(serde_json::to_value(result).unwrap()))
+}
+
+// Tried before `index`, which also matches these paths.  The static `tree`
+// segment sits where `index` expects a course key, and no course key is
+// `tree`, so this route can't shadow a user or a course.
+#[rocket::get("/<username>/tree/<coursekey..>", rank = 1)]
+fn tree(
+    username: String,
+    coursekey: PathBuf,
+) -> Result<Json<serde_json::Value>, status::Custom<String>> {
+    let user = User { username };
+    let coursekey = parse_coursekey(coursekey)?;
+    let app = build_app();
+    let result = app
+        .get_user_completion_tree(&user, &coursekey, true)
+        .map_err(error_response)?;
+    Ok(Json(serde_json::to_value(result).unwrap()))
 }
 
 fn main() {
-    rocket::ignite().mount("/", rocket::routes![index]).launch();
+    rocket::ignite().mount("/", rocket::routes![index, tree]).launch();
 }
@@ -5,9 +5,15 @@ use opaquekeys::{CourseKey, PartialUsageKey, UsageKey};
 
 use crate::ports::blockcompletions::BlockCompletionService;
 use crate::ports::enrollment::{Enrollment, EnrollmentQuery, EnrollmentService};
-use crate::ports::Result;
+use crate::ports::{Result, ServiceError};
 use crate::{BlockCompletion, User};
 
+/// MySQL server errors for rejected credentials.
+const ER_DBACCESS_DENIED_ERROR: u16 = 1044;
+const ER_ACCESS_DENIED_ERROR: u16 = 1045;
+/// MySQL server error for a query that waited too long for a lock.
+const ER_LOCK_WAIT_TIMEOUT: u16 = 1205;
+
 pub fn edxapp_connect() -> Option<mysql::Pool> {
     match mysql::Pool::new(format!(
         "mysql://{}:{}@{}:{}/{}",
@@ -42,55 +48,29 @@ impl BlockCompletionService for MySqlBlockCompletionAdapter {
                 JOIN auth_user
                     ON user_id = auth_user.id
             WHERE course_key = :coursekey";
-        let completions = self.conn
+        self.conn
             .prep_exec(
                 query,
                 params!{
                     "coursekey" => format!("{}", coursekey),
                 },
             )
-            .map(|result| {
-                result
-                    .map(|rowresult| rowresult.unwrap())
-                    .map(|row| {
-                        let (username, blockkeyraw, completion): (
-                            _,
-                            String,
-                            _,
-                        ) = mysql::from_row(row);
-                        let block_key: UsageKey = blockkeyraw
-                            .parse::<PartialUsageKey>()
-                            .unwrap()
-                            .map_into_course(coursekey.clone());
-                        let user = User { username };
-                        let user2 = user.clone();
-                        let blockkey2 = block_key.clone();
-                        (
-                            (user2, blockkey2),
-                            BlockCompletion {
-                                user,
-                                block_key,
-                                completion,
-                            },
-                        )
-                    })
-                    .collect()
-            })
-            .unwrap();
-        Ok(completions)
+            .map_err(mysql_error)?
+            .map(|row| blockcompletion_from_row(row.map_err(mysql_error)?, coursekey))
+            .collect()
     }
     fn get_user_blockcompletions(
         &self,
         user: &User,
         coursekey: &CourseKey,
     ) -> Result<BTreeMap<(User, UsageKey), BlockCompletion>> {
-        let query = "SELECT username, course_key, block_key, completion
+        let query = "SELECT username, block_key, completion
             FROM completion_blockcompletion
                 JOIN auth_user
                     ON user_id = auth_user.id
             WHERE course_key = :coursekey
                 AND username = :username";
-        let completions = self.conn
+        self.conn
             .prep_exec(
                 query,
                 params!{
@@ -98,32 +78,136 @@ impl BlockCompletionService for MySqlBlockCompletionAdapter {
                     "username" => user.username.to_string(),
                 },
             )
-            .map(|result| {
-                result
-                    .map(|rowresult| rowresult.unwrap())
-                    .map(|row| {
-                        let (username, _coursekey, blockkeyraw, completion) =
-                            mysql::from_row::<(String, String, String, f64)>(row);
-                        let block_key: UsageKey = blockkeyraw
-                            .parse::<PartialUsageKey>()
-                            .unwrap()
-                            .map_into_course(coursekey.clone());
-                        let user = User { username };
-                        let user2 = user.clone();
-                        let blockkey2 = block_key.clone();
-                        (
-                            (user2, blockkey2),
-                            BlockCompletion {
-                                user,
-                                block_key,
-                                completion,
-                            },
-                        )
-                    })
-                    .collect()
-            })
-            .unwrap();
-        Ok(completions)
+            .map_err(mysql_error)?
+            .map(|row| blockcompletion_from_row(row.map_err(mysql_error)?, coursekey))
+            .collect()
+    }
+    fn get_users_blockcompletions(
+        &self,
+        users: &[User],
+        coursekey: &CourseKey,
+    ) -> Result<BTreeMap<(User, UsageKey), BlockCompletion>> {
+        if users.is_empty() {
+            return Ok(BTreeMap::new());
+        }
+        let query = format!(
+            "SELECT username, block_key, completion
+            FROM completion_blockcompletion
+                JOIN auth_user
+                    ON user_id = auth_user.id
+            WHERE course_key = ?
+                AND username IN ({})",
+            placeholders(users.len())
+        );
+        let mut params: Vec<mysql::Value> = vec![coursekey.to_string().into()];
+        params.extend(users.iter().map(|user| user.username.clone().into()));
+        self.conn
+            .prep_exec(query, params)
+            .map_err(mysql_error)?
+            .map(|row| blockcompletion_from_row(row.map_err(mysql_error)?, coursekey))
+            .collect()
+    }
+    fn submit_completion(&self, blockcompletion: &BlockCompletion) -> Result<()> {
+        let result = self.conn
+            .prep_exec(SUBMIT_COMPLETION, submit_params(blockcompletion))
+            .map_err(mysql_error)?;
+        check_submitted(result.affected_rows())
+    }
+    fn submit_completions_batch(&self, blockcompletions: &[BlockCompletion]) -> Result<()> {
+        let mut transaction = self.conn
+            .start_transaction(false, None, None)
+            .map_err(mysql_error)?;
+        for blockcompletion in blockcompletions {
+            let affected_rows = transaction
+                .prep_exec(SUBMIT_COMPLETION, submit_params(blockcompletion))
+                .map_err(mysql_error)?
+                .affected_rows();
+            // Dropping the transaction without committing rolls it back.
+            check_submitted(affected_rows)?;
+        }
+        transaction.commit().map_err(mysql_error)
+    }
+}
+
+/// Insert or replace a block completion, looking up the user by username.
+/// Nothing is inserted if the user doesn't exist.
+const SUBMIT_COMPLETION: &str = "INSERT INTO completion_blockcompletion
+        (created, modified, user_id, course_key, block_key, block_type, completion)
+    SELECT NOW(6), NOW(6), auth_user.id, :coursekey, :blockkey, :blocktype, :completion
+        FROM auth_user
+        WHERE username = :username
+    ON DUPLICATE KEY UPDATE
+        modified = NOW(6),
+        completion = VALUES(completion)";
+
+fn submit_params(blockcompletion: &BlockCompletion) -> mysql::Params {
+    let block_key = &blockcompletion.block_key;
+    mysql::Params::from(params!{
+        "coursekey" => block_key.course_key().to_string(),
+        "blockkey" => block_key.to_string(),
+        "blocktype" => block_key.blocktype().to_owned(),
+        "completion" => blockcompletion.completion,
+        "username" => blockcompletion.user.username.clone(),
+    })
+}
+
+/// An upsert affects no rows only when the user wasn't found.
+fn check_submitted(affected_rows: u64) -> Result<()> {
+    if affected_rows == 0 {
+        Err(ServiceError::NotFound)
+    } else {
+        Ok(())
+    }
+}
+
+/// Convert a `(username, block_key, completion)` row into a block completion
+/// in `coursekey`.
+fn blockcompletion_from_row(
+    row: mysql::Row,
+    coursekey: &CourseKey,
+) -> Result<((User, UsageKey), BlockCompletion)> {
+    let (username, blockkeyraw, completion) = mysql::from_row_opt::<(String, String, f64)>(row)
+        .map_err(|err| ServiceError::InvalidData(Box::new(err)))?;
+    let block_key: UsageKey = blockkeyraw
+        .parse::<PartialUsageKey>()
+        .map_err(|err| {
+            ServiceError::InvalidData(format!("Bad block key {:?}: {}", blockkeyraw, err).into())
+        })?
+        .map_into_course(coursekey.clone());
+    let user = User { username };
+    Ok((
+        (user.clone(), block_key.clone()),
+        BlockCompletion {
+            user,
+            block_key,
+            completion,
+        },
+    ))
+}
+
+/// Map a MySQL failure onto a service error.
+fn mysql_error(err: mysql::Error) -> ServiceError {
+    match err {
+        mysql::Error::IoError(err) => match err.kind() {
+            std::io::ErrorKind::TimedOut => ServiceError::Timeout(Box::new(err)),
+            _ => ServiceError::Unavailable(Box::new(err)),
+        },
+        mysql::Error::DriverError(err) => match err {
+            mysql::DriverError::CouldNotConnect(_)
+            | mysql::DriverError::SetupError
+            | mysql::DriverError::PoisonedPoolMutex => ServiceError::Unavailable(Box::new(err)),
+            mysql::DriverError::Timeout => ServiceError::Timeout(Box::new(err)),
+            // Protocol and parameter errors won't go away on a retry.
+            _ => ServiceError::from_error(err),
+        },
+        mysql::Error::MySqlError(err) => match err.code {
+            ER_DBACCESS_DENIED_ERROR | ER_ACCESS_DENIED_ERROR => {
+                ServiceError::Authentication(Box::new(err))
+            }
+            ER_LOCK_WAIT_TIMEOUT => ServiceError::Timeout(Box::new(err)),
+            _ => ServiceError::from_error(err),
+        },
+        err => ServiceError::from_error(err),
     }
 }
 
@@ -135,28 +219,23 @@ impl MySqlEnrollmentAdapter {
     pub fn new(conn: mysql::Pool) -> MySqlEnrollmentAdapter {
         MySqlEnrollmentAdapter { conn }
     }
-    fn build_sql_from_query(&self, query: &EnrollmentQuery) -> String {
+    /// Build the enrollment query and its positional parameters.  An empty
+    /// list of users or courses doesn't filter, matching the stub adapter.
+    fn build_sql_from_query(&self, query: &EnrollmentQuery) -> (String, Vec<mysql::Value>) {
         let mut qstr = String::from("SELECT username, course_id FROM student_courseenrollment JOIN auth_user ON auth_user.id = user_id");
         let mut where_clauses = vec![];
+        let mut params: Vec<mysql::Value> = vec![];
         if let Some(ref courses) = query.courses {
-            where_clauses.push(format!(
-                " course_id IN ({})",
-                courses
-                    .iter()
-                    .map(|course_key| format!("'{}'", course_key))
-                    .collect::<Vec<_>>()
-                    .join(",")
-            ));
+            if !courses.is_empty() {
+                where_clauses.push(format!(" course_id IN ({})", placeholders(courses.len())));
+                params.extend(courses.iter().map(|course_key| course_key.to_string().into()));
+            }
         }
         if let Some(ref users) = query.users {
-            where_clauses.push(format!(
-                " username IN ('{}')",
-                users
-                    .iter()
-                    .map(|user| user.username.as_ref())
-                    .collect::<Vec<_>>()
-                    .join(",")
-            ));
+            if !users.is_empty() {
+                where_clauses.push(format!(" username IN ({})", placeholders(users.len())));
+                params.extend(users.iter().map(|user| user.username.clone().into()));
+            }
         }
         if !where_clauses.is_empty() {
             let mut first = true;
@@ -170,27 +249,31 @@ impl MySqlEnrollmentAdapter {
                 qstr.push_str(&clause);
             }
         }
-        qstr
+        (qstr, params)
     }
 }
 
+/// A comma-separated list of `count` positional placeholders.
+fn placeholders(count: usize) -> String {
+    vec!["?"; count].join(", ")
+}
+
 impl EnrollmentService for MySqlEnrollmentAdapter {
     fn query_enrollment(&self, query: &EnrollmentQuery) -> Result<Vec<Enrollment>> {
-        let qstr = self.build_sql_from_query(query);
-        let enrollments = self.conn
-            .prep_exec(qstr, mysql::Params::Empty)
-            .map(|result| {
-                result
-                    .map(|rowresult| rowresult.unwrap())
-                    .map(|row| {
-                        let (username, coursekey) = mysql::from_row::<(String, String)>(row);
-                        let user = User { username };
-                        let course = coursekey.parse().unwrap();
-                        Enrollment { user, course }
-                    })
-                    .collect()
+        let (qstr, params) = self.build_sql_from_query(query);
+        self.conn
+            .prep_exec(qstr, params)
+            .map_err(mysql_error)?
+            .map(|row| {
+                let row = row.map_err(mysql_error)?;
+                let (username, coursekey) = mysql::from_row_opt::<(String, String)>(row)
+                    .map_err(|err| ServiceError::InvalidData(Box::new(err)))?;
+                let course = coursekey.parse::<CourseKey>().map_err(|err| {
+                    ServiceError::InvalidData(format!("Bad course key {:?}: {}", coursekey, err).into())
+                })?;
+                let user = User { username };
+                Ok(Enrollment { user, course })
             })
-            .unwrap();
-        Ok(enrollments)
+            .collect()
     }
 }
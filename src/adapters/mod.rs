@@ -1,3 +1,4 @@
 pub mod db;
 pub mod rest;
 pub mod stubs;
+pub mod xblock;
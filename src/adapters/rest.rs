@@ -1,5 +1,6 @@
 use std::cell::RefCell;
 use std::collections::BTreeMap;
+use std::time::Duration;
 
 use reqwest;
 use serde_derive;
@@ -47,16 +48,48 @@ impl CourseAdapter {
             .post(&self.oauth_token_url)
             .form(&form)
             .send()
-            .map_err(ServiceError::from_error)?;
+            .map_err(request_error)?;
+        let resp = check_status(resp)?;
         let data: serde_json::Value =
-            serde_json::from_reader(resp).map_err(ServiceError::from_error)?;
+            serde_json::from_reader(resp).map_err(|err| ServiceError::InvalidData(Box::new(err)))?;
         Ok(data["access_token"]
             .as_str()
-            .ok_or_else(|| ServiceError::NotFound)?
+            .ok_or_else(|| ServiceError::Authentication("No access token in token response".into()))?
             .to_owned())
     }
 }
 
+/// Map a request that got no response onto a service error.
+fn request_error(err: reqwest::Error) -> ServiceError {
+    if err.is_timeout() {
+        ServiceError::Timeout(Box::new(err))
+    } else {
+        ServiceError::Unavailable(Box::new(err))
+    }
+}
+
+/// Map an unsuccessful response onto a service error.
+fn check_status(response: reqwest::Response) -> Result<reqwest::Response> {
+    let status = response.status();
+    if status.is_success() {
+        return Ok(response);
+    }
+    let message = format!("{} returned {}", response.url(), status);
+    Err(match status.as_u16() {
+        401 | 403 => ServiceError::Authentication(message.into()),
+        404 => ServiceError::NotFound,
+        429 => ServiceError::RateLimited {
+            retry_after: response
+                .headers()
+                .get(reqwest::header::RETRY_AFTER)
+                .and_then(|value| value.to_str().ok())
+                .and_then(|value| value.parse().ok())
+                .map(Duration::from_secs),
+        },
+        _ => ServiceError::Unavailable(message.into()),
+    })
+}
+
 impl Default for CourseAdapter {
     fn default() -> CourseAdapter {
         CourseAdapter::new()
@@ -85,19 +118,36 @@ impl CourseService for CourseAdapter {
             .bearer_auth(self.access_token.borrow().to_owned().unwrap())
             .query(&params)
             .send()
-            .map_err(ServiceError::from_error)?;
+            .map_err(request_error)?;
+        let response = match check_status(response) {
+            Ok(response) => response,
+            Err(err) => {
+                if let ServiceError::Authentication(_) = err {
+                    // The token may have expired.  Fetch a new one next time.
+                    self.access_token.replace(None);
+                }
+                return Err(err);
+            }
+        };
 
         let data: serde_json::Value =
-            serde_json::from_reader(response).map_err(ServiceError::from_error)?;
-        let blocks = data["blocks"].as_object().unwrap();
+            serde_json::from_reader(response).map_err(|err| ServiceError::InvalidData(Box::new(err)))?;
+        let blocks = data["blocks"]
+            .as_object()
+            .ok_or_else(|| ServiceError::InvalidData("No blocks in course response".into()))?;
         let mut output = BTreeMap::new();
         for (block, value) in blocks {
             let blockkey = UsageKey::new(coursekey.clone(), block.clone());
             if let Some(children) = value["children"].as_array() {
                 let children = children
-                    .into_iter()
-                    .map(|child| UsageKey::new(coursekey.clone(), child.as_str().unwrap().into()))
-                    .collect();
+                    .iter()
+                    .map(|child| match child.as_str() {
+                        Some(child) => Ok(UsageKey::new(coursekey.clone(), child.into())),
+                        None => Err(ServiceError::InvalidData(
+                            format!("Block {} has a child that is not a key: {}", block, child).into(),
+                        )),
+                    })
+                    .collect::<Result<_>>()?;
                 output.insert(blockkey, children);
             } else {
                 output.insert(blockkey, Vec::new());
@@ -1,4 +1,5 @@
-use std::collections::BTreeMap;
+use std::cell::RefCell;
+use std::collections::{BTreeMap, BTreeSet};
 
 use opaquekeys::{CourseKey, UsageKey};
 
@@ -9,12 +10,38 @@ use crate::ports::course::CourseService;
 use crate::ports::enrollment::{Enrollment, EnrollmentQuery, EnrollmentService};
 
 pub struct StubBlockCompletionAdapter {
-    blockcompletions: Vec<BlockCompletion>,
+    blockcompletions: RefCell<Vec<BlockCompletion>>,
+    /// Users that completions may be submitted for, like the rows of
+    /// `auth_user` in the MySQL adapter.
+    users: BTreeSet<User>,
 }
 
 impl StubBlockCompletionAdapter {
+    /// The users of `blockcompletions` are known users.
     pub fn new(blockcompletions: Vec<BlockCompletion>) -> StubBlockCompletionAdapter {
-        StubBlockCompletionAdapter { blockcompletions }
+        let users = blockcompletions.iter().map(|bc| bc.user.clone()).collect();
+        StubBlockCompletionAdapter {
+            blockcompletions: RefCell::new(blockcompletions),
+            users,
+        }
+    }
+
+    /// Add known users, who may have completions submitted without having
+    /// any yet.
+    pub fn with_users(mut self, users: Vec<User>) -> StubBlockCompletionAdapter {
+        self.users.extend(users);
+        self
+    }
+
+    fn upsert(&self, blockcompletion: &BlockCompletion) {
+        let mut blockcompletions = self.blockcompletions.borrow_mut();
+        let existing = blockcompletions.iter_mut().find(|bc| {
+            bc.user == blockcompletion.user && bc.block_key == blockcompletion.block_key
+        });
+        match existing {
+            Some(existing) => existing.completion = blockcompletion.completion,
+            None => blockcompletions.push(blockcompletion.clone()),
+        }
     }
 }
 
@@ -24,6 +51,7 @@ impl BlockCompletionService for StubBlockCompletionAdapter {
         coursekey: &CourseKey,
     ) -> Result<BTreeMap<(User, UsageKey), BlockCompletion>> {
         Ok(self.blockcompletions
+            .borrow()
             .iter()
             .filter(|bc| bc.block_key.course_key() == coursekey)
             .map(|bc| ((bc.user.clone(), bc.block_key.clone()), bc.clone()))
@@ -35,12 +63,40 @@ impl BlockCompletionService for StubBlockCompletionAdapter {
         coursekey: &CourseKey,
     ) -> Result<BTreeMap<(User, UsageKey), BlockCompletion>> {
         Ok(self.blockcompletions
+            .borrow()
             .iter()
             .filter(|bc| bc.block_key.course_key() == coursekey)
             .filter(|bc| &bc.user == user)
             .map(|bc| ((bc.user.clone(), bc.block_key.clone()), bc.clone()))
             .collect())
     }
+    fn get_users_blockcompletions(
+        &self,
+        users: &[User],
+        coursekey: &CourseKey,
+    ) -> Result<BTreeMap<(User, UsageKey), BlockCompletion>> {
+        Ok(self.blockcompletions
+            .borrow()
+            .iter()
+            .filter(|bc| bc.block_key.course_key() == coursekey)
+            .filter(|bc| users.contains(&bc.user))
+            .map(|bc| ((bc.user.clone(), bc.block_key.clone()), bc.clone()))
+            .collect())
+    }
+    fn submit_completion(&self, blockcompletion: &BlockCompletion) -> Result<()> {
+        self.submit_completions_batch(std::slice::from_ref(blockcompletion))
+    }
+    /// Nothing is recorded unless every user is known, as in the MySQL
+    /// adapter's transaction.
+    fn submit_completions_batch(&self, blockcompletions: &[BlockCompletion]) -> Result<()> {
+        if blockcompletions.iter().any(|bc| !self.users.contains(&bc.user)) {
+            return Err(ServiceError::NotFound);
+        }
+        for blockcompletion in blockcompletions {
+            self.upsert(blockcompletion);
+        }
+        Ok(())
+    }
 }
 pub struct StubEnrollmentAdapter {
     enrollments: Vec<Enrollment>,
@@ -88,7 +144,8 @@ pub struct StubCourseAdapter {
 
 impl StubCourseAdapter {
     /// This does not check that the usage keys actually belong to the right
-    /// course, or even the same course.
+    /// course, or even the same course.  `Course::from_structure` rejects
+    /// structures that mix courses.
     pub fn new(
         coursekey: CourseKey,
         blocks: BTreeMap<UsageKey, Vec<UsageKey>>,
@@ -0,0 +1,72 @@
+use std::collections::BTreeMap;
+use std::ffi::OsStr;
+use std::path::{Path, PathBuf};
+
+use crate::ports::{Result, ServiceError};
+use crate::ports::xblock::XBlockModeService;
+use crate::xblock::{get_xblock_modes, CompletionMode};
+
+/// Serves the xblock modes built into this crate.
+#[derive(Clone, Copy, Debug, Default)]
+pub struct BuiltinXBlockModeAdapter;
+
+impl BuiltinXBlockModeAdapter {
+    pub fn new() -> BuiltinXBlockModeAdapter {
+        BuiltinXBlockModeAdapter
+    }
+}
+
+impl XBlockModeService for BuiltinXBlockModeAdapter {
+    fn get_xblock_modes(&self) -> Result<BTreeMap<String, CompletionMode>> {
+        Ok(get_xblock_modes())
+    }
+}
+
+/// Serves xblock modes from a config file, layered over the built-in modes.
+///
+/// The file is a flat table of blocktype to mode.  Files ending in `.toml`
+/// are read as TOML:
+///
+/// ```toml
+/// lti_consumer = "completable"
+/// drag-and-drop-v2 = "completable"
+/// ```
+///
+/// and anything else as JSON:
+///
+/// ```json
+/// {"lti_consumer": "completable", "drag-and-drop-v2": "completable"}
+/// ```
+///
+/// The file is read once per `App` call, so changes take effect without a
+/// restart.
+#[derive(Clone, Debug)]
+pub struct FileXBlockModeAdapter {
+    path: PathBuf,
+}
+
+impl FileXBlockModeAdapter {
+    pub fn new<P: AsRef<Path>>(path: P) -> FileXBlockModeAdapter {
+        FileXBlockModeAdapter {
+            path: path.as_ref().to_owned(),
+        }
+    }
+}
+
+impl XBlockModeService for FileXBlockModeAdapter {
+    fn get_xblock_modes(&self) -> Result<BTreeMap<String, CompletionMode>> {
+        let contents = std::fs::read_to_string(&self.path).map_err(|err| match err.kind() {
+            std::io::ErrorKind::NotFound => ServiceError::NotFound,
+            _ => ServiceError::Unavailable(Box::new(err)),
+        })?;
+        let configured: BTreeMap<String, CompletionMode> =
+            if self.path.extension() == Some(OsStr::new("toml")) {
+                toml::from_str(&contents).map_err(|err| ServiceError::InvalidData(Box::new(err)))?
+            } else {
+                serde_json::from_str(&contents).map_err(|err| ServiceError::InvalidData(Box::new(err)))?
+            };
+        let mut modes = get_xblock_modes();
+        modes.extend(configured);
+        Ok(modes)
+    }
+}
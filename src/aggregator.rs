@@ -1,122 +1,1189 @@
-use std::collections::BTreeMap;
+use std::collections::{BTreeMap, BTreeSet};
+use std::sync::Arc;
 
 use opaquekeys::{CourseKey, UsageKey};
+use serde_derive::Serialize;
 
-use crate::{Aggregator, BlockCompletion, User};
+use crate::{Aggregator, BlockCompletion, CompletionStatus, User};
+use crate::strategy::{AggregationStrategy, Score, Sum};
 use crate::xblock::{get_xblock_modes, CompletionMode, XBlock};
 
+/// Reasons a course structure can't be turned into a `Course`.
+#[derive(Clone, Debug, Eq, PartialEq)]
+pub enum StructureError {
+    /// The structure has no `course` block to use as its root.
+    NoCourseRoot,
+    /// The structure has more than one `course` block.
+    MultipleCourseRoots(Vec<UsageKey>),
+    /// The requested root block does not appear in the structure.
+    RootNotFound(UsageKey),
+    /// A block is its own ancestor.  The path starts and ends with the same
+    /// block.
+    Cycle(Vec<UsageKey>),
+    /// A block has a child that belongs to a different course.
+    ForeignBlock { parent: UsageKey, child: UsageKey },
+    /// Blocks in the structure that can't be reached from the course root.
+    OrphanBlocks(Vec<UsageKey>),
+    /// Blocktypes with no completion mode, under
+    /// `UnknownBlockTypePolicy::Error`.
+    UnknownBlockTypes(Vec<String>),
+    /// A completable block would be counted more than once by an aggregator:
+    /// through two children of a non-additive aggregator, or through two
+    /// non-additive aggregators under an additive one.
+    SharedNonAdditiveBlock { aggregator: UsageKey, block: UsageKey },
+}
+
+impl std::fmt::Display for StructureError {
+    fn fmt(&self, f: &mut std::fmt::Formatter) -> std::fmt::Result {
+        match self {
+            StructureError::NoCourseRoot => write!(f, "No course block found in structure"),
+            StructureError::MultipleCourseRoots(keys) => {
+                write!(f, "Multiple course blocks found in structure: {}", join_keys(keys))
+            }
+            StructureError::RootNotFound(key) => write!(f, "Block {} not found in structure", key),
+            StructureError::Cycle(keys) => write!(f, "Cycle found in structure: {}", join_keys(keys)),
+            StructureError::ForeignBlock { parent, child } => write!(
+                f,
+                "Block {} has child {} from another course",
+                parent, child
+            ),
+            StructureError::OrphanBlocks(keys) => {
+                write!(f, "Blocks not reachable from the course root: {}", join_keys(keys))
+            }
+            StructureError::UnknownBlockTypes(blocktypes) => {
+                write!(f, "Unknown blocktypes in structure: {}", blocktypes.join(", "))
+            }
+            StructureError::SharedNonAdditiveBlock { aggregator, block } => write!(
+                f,
+                "Block {} is counted more than once in {}",
+                block, aggregator
+            ),
+        }
+    }
+}
+
+impl std::error::Error for StructureError {}
+
+fn join_keys(keys: &[UsageKey]) -> String {
+    keys.iter()
+        .map(UsageKey::to_string)
+        .collect::<Vec<_>>()
+        .join(", ")
+}
+
+/// Decides how much credit a block's raw completion earns.
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub struct Threshold {
+    /// Completions below this value earn no credit.
+    pub minimum: f64,
+    /// Whether completions at or above `minimum` earn full credit, rather
+    /// than being credited as-is.
+    pub binary: bool,
+}
+
+impl Threshold {
+    /// Credit completions at or above `minimum` as-is, and others as 0.
+    ///
+    /// # Panics
+    ///
+    /// Panics if `minimum` is not in (0, 1].
+    pub fn partial(minimum: f64) -> Threshold {
+        check_threshold(minimum);
+        Threshold {
+            minimum,
+            binary: false,
+        }
+    }
+
+    /// Credit completions at or above `minimum` as 1, and others as 0.
+    ///
+    /// # Panics
+    ///
+    /// Panics if `minimum` is not in (0, 1].
+    pub fn binary(minimum: f64) -> Threshold {
+        check_threshold(minimum);
+        Threshold {
+            minimum,
+            binary: true,
+        }
+    }
+
+    pub fn credit(&self, completion: f64) -> f64 {
+        if completion < self.minimum {
+            0.0
+        } else if self.binary {
+            1.0
+        } else {
+            completion
+        }
+    }
+}
+
+/// A user's completion of a block and its descendants, shaped like the
+/// course outline.
+///
+/// A block shared between several parents appears under each of them.
+#[derive(Clone, Debug, PartialEq, Serialize)]
+pub struct CompletionTree {
+    pub block_key: UsageKey,
+    pub blocktype: String,
+    pub mode: CompletionMode,
+    pub earned: f64,
+    pub possible: f64,
+    /// `None` if the block has nothing to complete.
+    pub percent: Option<f64>,
+    pub status: CompletionStatus,
+    /// The raw completion of a completable block.
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub completion: Option<f64>,
+    pub children: Vec<CompletionTree>,
+}
+
+/// A completable block that the user has not fully completed.
+#[derive(Clone, Debug, PartialEq, Serialize)]
+pub struct IncompleteBlock {
+    pub block_key: UsageKey,
+    pub blocktype: String,
+    /// The user's raw completion of the block.
+    pub completion: f64,
+}
+
+/// Why a block does not count toward completion.
+#[derive(Clone, Copy, Debug, Eq, PartialEq, Serialize)]
+#[serde(rename_all = "snake_case")]
+pub enum ExclusionReason {
+    /// The blocktype's completion mode is excluded.
+    BlockType,
+    /// The blocktype is excluded in this course.
+    CourseOverride,
+    /// The block itself is excluded.
+    BlockOverride,
+    /// The blocktype has no completion mode, and unknown blocktypes are
+    /// excluded.
+    UnknownBlockType,
+    /// The block is optional.  It has its own completion, but doesn't count
+    /// toward its parents'.
+    Optional,
+}
+
+/// Where a block's completion mode came from, from most to least specific.
+#[derive(Clone, Copy, Debug, Eq, PartialEq, Serialize)]
+#[serde(rename_all = "snake_case")]
+pub enum ModeSource {
+    /// An override for the individual block.
+    Block,
+    /// An override for the blocktype in the block's course.
+    Course,
+    /// The blocktype's mode.
+    BlockType,
+    /// The blocktype has no mode, so the unknown blocktype policy applies.
+    Default,
+}
+
+impl ModeSource {
+    fn exclusion_reason(self) -> ExclusionReason {
+        match self {
+            ModeSource::Block => ExclusionReason::BlockOverride,
+            ModeSource::Course => ExclusionReason::CourseOverride,
+            ModeSource::BlockType => ExclusionReason::BlockType,
+            ModeSource::Default => ExclusionReason::UnknownBlockType,
+        }
+    }
+}
+
+/// How to aggregate blocks whose blocktype has no completion mode.
+#[derive(Clone, Copy, Debug, Default, Eq, PartialEq)]
+pub enum UnknownBlockTypePolicy {
+    /// Count unknown blocks as completable.
+    #[default]
+    Completable,
+    /// Leave unknown blocks out of completion.
+    Excluded,
+    /// Refuse to build a course with unknown blocks, with
+    /// `StructureError::UnknownBlockTypes`.
+    Error,
+}
+
+/// Something worth knowing about how a course was aggregated, that didn't
+/// stop it from being aggregated.
+#[derive(Clone, Debug, Eq, Ord, PartialEq, PartialOrd, Serialize)]
+#[serde(tag = "kind", rename_all = "snake_case")]
+pub enum AggregationWarning {
+    /// Blocks of this blocktype were aggregated according to the unknown
+    /// blocktype policy.
+    UnknownBlockType { blocktype: String },
+}
+
+/// The completion mode a block was aggregated with.
+#[derive(Clone, Debug, PartialEq, Serialize)]
+pub struct EffectiveMode {
+    pub block_key: UsageKey,
+    pub blocktype: String,
+    pub mode: CompletionMode,
+    pub source: ModeSource,
+}
+
+/// A block that does not count toward completion.
+#[derive(Clone, Debug, PartialEq, Serialize)]
+pub struct ExcludedBlock {
+    pub block_key: UsageKey,
+    pub blocktype: String,
+    pub reason: ExclusionReason,
+}
+
+/// What a user has left to do under a block.
+#[derive(Clone, Debug, PartialEq, Serialize)]
+pub struct Remaining {
+    pub block_key: UsageKey,
+    /// Incomplete completable descendants, in course order.
+    pub incomplete: Vec<IncompleteBlock>,
+    /// Excluded descendants, in course order.  The descendants of an
+    /// excluded block are not listed.
+    pub excluded: Vec<ExcludedBlock>,
+}
+
+/// Settings that control how completion is aggregated.
+///
+/// The default configuration uses the built-in xblock modes, weighs every
+/// completable block equally, credits completions as-is, and sums the
+/// completion of every aggregator's descendants.
+#[derive(Clone, Debug)]
+pub struct AggregationConfig {
+    xblock_modes: BTreeMap<String, CompletionMode>,
+    course_modes: BTreeMap<(CourseKey, String), CompletionMode>,
+    block_modes: BTreeMap<UsageKey, CompletionMode>,
+    unknown_blocktype_policy: UnknownBlockTypePolicy,
+    blocktype_weights: BTreeMap<String, f64>,
+    block_weights: BTreeMap<UsageKey, f64>,
+    blocktype_strategies: BTreeMap<String, Arc<dyn AggregationStrategy>>,
+    block_strategies: BTreeMap<UsageKey, Arc<dyn AggregationStrategy>>,
+    default_threshold: Option<Threshold>,
+    blocktype_thresholds: BTreeMap<String, Threshold>,
+    omit_not_applicable: bool,
+}
+
+impl Default for AggregationConfig {
+    fn default() -> AggregationConfig {
+        AggregationConfig {
+            xblock_modes: get_xblock_modes(),
+            course_modes: BTreeMap::new(),
+            block_modes: BTreeMap::new(),
+            unknown_blocktype_policy: UnknownBlockTypePolicy::default(),
+            blocktype_weights: BTreeMap::new(),
+            block_weights: BTreeMap::new(),
+            blocktype_strategies: BTreeMap::new(),
+            block_strategies: BTreeMap::new(),
+            default_threshold: None,
+            blocktype_thresholds: BTreeMap::new(),
+            omit_not_applicable: false,
+        }
+    }
+}
+
+impl AggregationConfig {
+    /// Replace the completion mode of every blocktype with the modes served
+    /// by an `App`'s `XBlockModeService`, which is their only source.
+    pub(crate) fn with_xblock_modes(mut self, xblock_modes: BTreeMap<String, CompletionMode>) -> AggregationConfig {
+        self.xblock_modes = xblock_modes;
+        self
+    }
+
+    /// Override the completion mode of every block of a blocktype in one
+    /// course.  This takes precedence over the blocktype's mode.
+    pub fn with_course_mode(
+        mut self,
+        course: CourseKey,
+        blocktype: &str,
+        mode: CompletionMode,
+    ) -> AggregationConfig {
+        self.course_modes.insert((course, blocktype.to_owned()), mode);
+        self
+    }
+
+    /// Override the completion mode of an individual block, such as a
+    /// course-info banner that shouldn't count.  This takes precedence over
+    /// course overrides.
+    pub fn with_block_mode(mut self, block: UsageKey, mode: CompletionMode) -> AggregationConfig {
+        self.block_modes.insert(block, mode);
+        self
+    }
+
+    /// Set how to aggregate blocks whose blocktype has no completion mode.
+    pub fn with_unknown_blocktype_policy(mut self, policy: UnknownBlockTypePolicy) -> AggregationConfig {
+        self.unknown_blocktype_policy = policy;
+        self
+    }
+
+    /// The completion mode of a block.
+    pub fn mode(&self, block: &UsageKey) -> CompletionMode {
+        self.mode_source(block).0
+    }
+
+    /// The completion mode of a block, and where it came from.
+    pub fn mode_source(&self, block: &UsageKey) -> (CompletionMode, ModeSource) {
+        if let Some(&mode) = self.block_modes.get(block) {
+            return (mode, ModeSource::Block);
+        }
+        let course_mode = self
+            .course_modes
+            .get(&(block.course_key().clone(), block.blocktype().to_owned()));
+        if let Some(&mode) = course_mode {
+            return (mode, ModeSource::Course);
+        }
+        match self.xblock_modes.get(block.blocktype()) {
+            Some(&mode) => (mode, ModeSource::BlockType),
+            None => match self.unknown_blocktype_policy {
+                UnknownBlockTypePolicy::Excluded => (CompletionMode::Excluded, ModeSource::Default),
+                _ => (CompletionMode::Completable, ModeSource::Default),
+            },
+        }
+    }
+
+    /// Set the default weight for every block of a blocktype.
+    ///
+    /// # Panics
+    ///
+    /// Panics if `weight` is negative, NaN or infinite.
+    pub fn with_blocktype_weight(mut self, blocktype: &str, weight: f64) -> AggregationConfig {
+        check_weight(weight);
+        self.blocktype_weights.insert(blocktype.to_owned(), weight);
+        self
+    }
+
+    /// Set the weight of individual blocks, such as weights taken from block
+    /// metadata.  These take precedence over blocktype weights.
+    ///
+    /// # Panics
+    ///
+    /// Panics if any weight is negative, NaN or infinite.
+    pub fn with_block_weights(mut self, weights: BTreeMap<UsageKey, f64>) -> AggregationConfig {
+        weights.values().cloned().for_each(check_weight);
+        self.block_weights.extend(weights);
+        self
+    }
+
+    /// The weight a completable block contributes to `possible`.
+    pub fn weight(&self, block: &UsageKey) -> f64 {
+        self.block_weights
+            .get(block)
+            .or_else(|| self.blocktype_weights.get(block.blocktype()))
+            .cloned()
+            .unwrap_or(1.0)
+    }
+
+    /// Set the aggregation strategy for every aggregator of a blocktype.
+    pub fn with_blocktype_strategy<S>(mut self, blocktype: &str, strategy: S) -> AggregationConfig
+    where
+        S: AggregationStrategy + 'static,
+    {
+        self.blocktype_strategies
+            .insert(blocktype.to_owned(), Arc::new(strategy));
+        self
+    }
+
+    /// Set the aggregation strategy for an individual aggregator block.  This
+    /// takes precedence over the blocktype strategy.
+    pub fn with_block_strategy<S>(mut self, block: UsageKey, strategy: S) -> AggregationConfig
+    where
+        S: AggregationStrategy + 'static,
+    {
+        self.block_strategies.insert(block, Arc::new(strategy));
+        self
+    }
+
+    /// The strategy used to aggregate an aggregator block.
+    pub fn strategy(&self, block: &UsageKey) -> Arc<dyn AggregationStrategy> {
+        self.block_strategies
+            .get(block)
+            .or_else(|| self.blocktype_strategies.get(block.blocktype()))
+            .cloned()
+            .unwrap_or_else(|| Arc::new(Sum))
+    }
+
+    /// Set the threshold for every blocktype without one of its own.
+    ///
+    /// # Panics
+    ///
+    /// Panics if the threshold's `minimum` is not in (0, 1].
+    pub fn with_threshold(mut self, threshold: Threshold) -> AggregationConfig {
+        check_threshold(threshold.minimum);
+        self.default_threshold = Some(threshold);
+        self
+    }
+
+    /// Set the threshold for every block of a blocktype.
+    ///
+    /// # Panics
+    ///
+    /// Panics if the threshold's `minimum` is not in (0, 1].
+    pub fn with_blocktype_threshold(mut self, blocktype: &str, threshold: Threshold) -> AggregationConfig {
+        check_threshold(threshold.minimum);
+        self.blocktype_thresholds
+            .insert(blocktype.to_owned(), threshold);
+        self
+    }
+
+    /// Leave blocks with nothing to complete out of aggregation output.
+    pub fn with_omit_not_applicable(mut self, omit_not_applicable: bool) -> AggregationConfig {
+        self.omit_not_applicable = omit_not_applicable;
+        self
+    }
+
+    /// The threshold applied to a block's raw completion, if any.
+    pub fn threshold(&self, block: &UsageKey) -> Option<Threshold> {
+        self.blocktype_thresholds
+            .get(block.blocktype())
+            .cloned()
+            .or(self.default_threshold)
+    }
+}
+
+/// Weights scale `possible`, so they must be finite and not negative.
+fn check_weight(weight: f64) {
+    assert!(
+        weight.is_finite() && weight >= 0.0,
+        "Weight must be finite and not negative, got {}",
+        weight
+    );
+}
+
+/// A minimum of 0 would credit blocks the user never touched, and NaN would
+/// credit every block, so a threshold's minimum must be in (0, 1].
+fn check_threshold(minimum: f64) {
+    assert!(
+        minimum > 0.0 && minimum <= 1.0,
+        "Threshold minimum must be greater than 0 and at most 1, got {}",
+        minimum
+    );
+}
+
+/// A course (or course subtree), compiled for aggregation.
+///
+/// Courses may be DAGs rather than trees, so every block is stored exactly
+/// once in `nodes`, in post-order, with the root last.  Each completable
+/// block is also given a dense leaf index, so that a user's completions can
+/// be held in a plain vector, and aggregating a user is one linear pass over
+/// `nodes`.
 pub struct Course {
     coursekey: CourseKey,
-    root: CourseNode,
+    nodes: Vec<CourseNode>,
+    /// Maps each block to its index in `nodes`.
+    indices: BTreeMap<UsageKey, usize>,
+    /// Maps each completable block to its leaf index.
+    leaves: BTreeMap<UsageKey, usize>,
+    /// For each node, the indices of the nodes that take it as an input.
+    dependents: Vec<Vec<usize>>,
+    omit_not_applicable: bool,
 }
 
 impl Course {
-    pub fn from_structure(structure: &BTreeMap<UsageKey, Vec<UsageKey>>) -> Course {
-        let mut rootblock = None;
-        for usagekey in structure.keys() {
-            if usagekey.blocktype() == "course" {
-                // This will break for course subgraphs, which won't have a course.
-                rootblock = Some(usagekey);
+    /// Build a course rooted at its `course` block.
+    ///
+    /// The structure must have exactly one `course` block, every block must
+    /// be reachable from it, and it must not contain cycles or blocks from
+    /// other courses.
+    pub fn from_structure(
+        structure: &BTreeMap<UsageKey, Vec<UsageKey>>,
+        config: &AggregationConfig,
+    ) -> Result<Course, StructureError> {
+        let blocks = all_blocks(structure);
+        let roots: Vec<&UsageKey> = blocks
+            .iter()
+            .cloned()
+            .filter(|usagekey| usagekey.blocktype() == "course")
+            .collect();
+        let rootblock = match roots.as_slice() {
+            [] => return Err(StructureError::NoCourseRoot),
+            [rootblock] => *rootblock,
+            _ => {
+                return Err(StructureError::MultipleCourseRoots(
+                    roots.into_iter().cloned().collect(),
+                ))
+            }
+        };
+        let reachable = check_reachable(rootblock, structure)?;
+        let orphans: Vec<UsageKey> = blocks
+            .into_iter()
+            .filter(|block| !reachable.contains(block))
+            .cloned()
+            .collect();
+        if !orphans.is_empty() {
+            return Err(StructureError::OrphanBlocks(orphans));
+        }
+        Course::build(rootblock, structure, config)
+    }
+
+    /// Build a course rooted at any block in the structure, such as a chapter
+    /// or sequential.  Only that block and its descendants are included, and
+    /// they must not contain cycles or blocks from other courses.
+    pub fn from_root(
+        rootblock: &UsageKey,
+        structure: &BTreeMap<UsageKey, Vec<UsageKey>>,
+        config: &AggregationConfig,
+    ) -> Result<Course, StructureError> {
+        let in_structure = structure.contains_key(rootblock)
+            || structure.values().any(|children| children.contains(rootblock));
+        if !in_structure {
+            return Err(StructureError::RootNotFound(rootblock.clone()));
+        }
+        check_reachable(rootblock, structure)?;
+        Course::build(rootblock, structure, config)
+    }
+
+    /// Build a course from a structure that has already been checked.
+    fn build(
+        rootblock: &UsageKey,
+        structure: &BTreeMap<UsageKey, Vec<UsageKey>>,
+        config: &AggregationConfig,
+    ) -> Result<Course, StructureError> {
+        let mut builder = CourseBuilder {
+            structure,
+            config,
+            nodes: Vec::new(),
+            indices: BTreeMap::new(),
+            leaf_count: 0,
+            leaf_descendants: Vec::new(),
+            error: None,
+        };
+        builder.add(rootblock);
+        if let Some(err) = builder.error {
+            return Err(err);
+        }
+        let leaves = builder
+            .nodes
+            .iter()
+            .filter_map(|node| node.leaf.map(|leaf| (node.blockkey.clone(), leaf)))
+            .collect();
+        let mut dependents = vec![Vec::new(); builder.nodes.len()];
+        for (index, node) in builder.nodes.iter().enumerate() {
+            for &input in &node.inputs {
+                dependents[input].push(index);
             }
         }
-        let rootblock = rootblock.unwrap(); //CRASH!!!
-        let xblock_modes = get_xblock_modes();
-        Course {
+        let course = Course {
             coursekey: rootblock.course_key().clone(),
-            root: CourseNode::new(rootblock.clone(), structure, &xblock_modes),
+            nodes: builder.nodes,
+            indices: builder.indices,
+            leaves,
+            dependents,
+            omit_not_applicable: config.omit_not_applicable,
+        };
+        let unknown = course.unknown_blocktypes();
+        if config.unknown_blocktype_policy == UnknownBlockTypePolicy::Error && !unknown.is_empty() {
+            return Err(StructureError::UnknownBlockTypes(unknown.into_iter().collect()));
         }
+        Ok(course)
     }
 
+    /// Aggregate a user's completion for every aggregator block, in post-order.
     pub fn aggregate(
         &self,
         user: &User,
         completions: &BTreeMap<(User, UsageKey), BlockCompletion>,
     ) -> Vec<Aggregator> {
-        self.root.aggregate(user, completions).0
+        let completions = self.completion_vector(completions.values().filter(|bc| &bc.user == user));
+        self.aggregate_completions(user, &completions)
+    }
+
+    /// Aggregate only the root block of the course.
+    pub fn aggregate_root(
+        &self,
+        user: &User,
+        completions: &BTreeMap<(User, UsageKey), BlockCompletion>,
+    ) -> Aggregator {
+        let completions = self.completion_vector(completions.values().filter(|bc| &bc.user == user));
+        let scores = self.scores(&completions);
+        let root = self.nodes.len() - 1;
+        self.nodes[root].aggregator(user, &scores[root])
+    }
+
+    /// Aggregate a user's completion from a completion vector, as built by
+    /// `completion_vector` or `completion_vectors`.  A short (or empty)
+    /// vector leaves the missing blocks incomplete.
+    pub fn aggregate_completions(&self, user: &User, completions: &[f64]) -> Vec<Aggregator> {
+        let scores = self.scores(completions);
+        self.nodes
+            .iter()
+            .zip(&scores)
+            .filter(|(node, _)| node.is_aggregator())
+            .filter(|(_, score)| self.is_reported(score))
+            .map(|(node, score)| node.aggregator(user, score))
+            .collect()
+    }
+
+    /// Collect one user's block completions into a vector indexed by leaf.
+    /// Blocks that aren't completable blocks of this course are ignored.
+    pub fn completion_vector<'a, I>(&self, completions: I) -> Vec<f64>
+    where
+        I: IntoIterator<Item = &'a BlockCompletion>,
+    {
+        let mut vector = vec![0.0; self.leaves.len()];
+        for bc in completions {
+            if let Some(&leaf) = self.leaves.get(&bc.block_key) {
+                vector[leaf] = bc.completion;
+            }
+        }
+        vector
+    }
+
+    /// Collect many users' block completions into one completion vector per
+    /// user, in a single pass.  Users with no completions are left out.
+    pub fn completion_vectors<'a, I>(&self, completions: I) -> BTreeMap<&'a User, Vec<f64>>
+    where
+        I: IntoIterator<Item = &'a BlockCompletion>,
+    {
+        let mut vectors = BTreeMap::new();
+        for bc in completions {
+            if let Some(&leaf) = self.leaves.get(&bc.block_key) {
+                vectors
+                    .entry(&bc.user)
+                    .or_insert_with(|| vec![0.0; self.leaves.len()])[leaf] = bc.completion;
+            }
+        }
+        vectors
+    }
+
+    /// Aggregate a user's completion into a tree that mirrors the course
+    /// outline.  Unless `include_leaves` is set, only aggregator blocks are
+    /// included.  The root is always included, even if it has nothing to
+    /// complete.
+    pub fn aggregate_tree(
+        &self,
+        user: &User,
+        completions: &BTreeMap<(User, UsageKey), BlockCompletion>,
+        include_leaves: bool,
+    ) -> CompletionTree {
+        let completions = self.completion_vector(completions.values().filter(|bc| &bc.user == user));
+        let scores = self.scores(&completions);
+        self.tree(self.nodes.len() - 1, &completions, &scores, include_leaves)
+    }
+
+    fn tree(
+        &self,
+        index: usize,
+        completions: &[f64],
+        scores: &[Score],
+        include_leaves: bool,
+    ) -> CompletionTree {
+        let node = &self.nodes[index];
+        let score = scores[index];
+        let children = node.children
+            .iter()
+            .filter(|&&child| include_leaves || self.nodes[child].is_aggregator())
+            .filter(|&&child| self.is_reported(&scores[child]))
+            .map(|&child| self.tree(child, completions, scores, include_leaves))
+            .collect();
+        CompletionTree {
+            block_key: node.blockkey.clone(),
+            blocktype: node.xblock.name.clone(),
+            mode: node.xblock.mode,
+            earned: score.earned,
+            possible: score.possible,
+            percent: match score.status() {
+                CompletionStatus::NotApplicable => None,
+                _ => Some(score.percent()),
+            },
+            status: score.status(),
+            completion: node.leaf.map(|leaf| completions[leaf]),
+            children,
+        }
+    }
+
+    /// List what a user has left to complete under a block, or `None` if the
+    /// block isn't part of this course.  Each shared block is listed once.
+    /// Optional blocks are listed as excluded, unless `block` is itself an
+    /// optional aggregator.
+    pub fn remaining(
+        &self,
+        user: &User,
+        completions: &BTreeMap<(User, UsageKey), BlockCompletion>,
+        block: &UsageKey,
+    ) -> Option<Remaining> {
+        let start = *self.indices.get(block)?;
+        let completions = self.completion_vector(completions.values().filter(|bc| &bc.user == user));
+        let mut remaining = Remaining {
+            block_key: block.clone(),
+            incomplete: Vec::new(),
+            excluded: Vec::new(),
+        };
+        let mut visited = BTreeSet::new();
+        let mut stack = vec![start];
+        while let Some(index) = stack.pop() {
+            if !visited.insert(index) {
+                continue;
+            }
+            let node = &self.nodes[index];
+            match node.xblock.mode {
+                CompletionMode::Excluded => remaining.excluded.push(ExcludedBlock {
+                    block_key: node.blockkey.clone(),
+                    blocktype: node.xblock.name.clone(),
+                    reason: node.mode_source.exclusion_reason(),
+                }),
+                CompletionMode::Optional if index == start && node.is_aggregator() => {
+                    stack.extend(node.children.iter().rev())
+                }
+                CompletionMode::Optional => remaining.excluded.push(ExcludedBlock {
+                    block_key: node.blockkey.clone(),
+                    blocktype: node.xblock.name.clone(),
+                    reason: ExclusionReason::Optional,
+                }),
+                CompletionMode::Completable => {
+                    let completion = node.leaf.map_or(0.0, |leaf| completions[leaf]);
+                    if !node.leaf_score(completion).is_complete() {
+                        remaining.incomplete.push(IncompleteBlock {
+                            block_key: node.blockkey.clone(),
+                            blocktype: node.xblock.name.clone(),
+                            completion,
+                        });
+                    }
+                }
+                CompletionMode::Aggregator => stack.extend(node.children.iter().rev()),
+            }
+        }
+        Some(remaining)
+    }
+
+    /// Find the first block under `block`, in course order, that the user
+    /// has not completed.  Returns `None` if everything is complete, or if
+    /// the block isn't part of this course.
+    pub fn next_incomplete(
+        &self,
+        user: &User,
+        completions: &BTreeMap<(User, UsageKey), BlockCompletion>,
+        block: &UsageKey,
+    ) -> Option<UsageKey> {
+        self.remaining(user, completions, block)?
+            .incomplete
+            .into_iter()
+            .next()
+            .map(|incomplete| incomplete.block_key)
+    }
+
+    /// List the completion mode of every block in the course, and where it
+    /// came from, in post-order.  Descendants of completable and excluded
+    /// blocks are not part of the course, so they are not listed.
+    pub fn effective_modes(&self) -> Vec<EffectiveMode> {
+        self.nodes
+            .iter()
+            .map(|node| EffectiveMode {
+                block_key: node.blockkey.clone(),
+                blocktype: node.xblock.name.clone(),
+                mode: node.xblock.mode,
+                source: node.mode_source,
+            })
+            .collect()
+    }
+
+    /// The blocktypes in the course with no completion mode.
+    pub fn unknown_blocktypes(&self) -> BTreeSet<String> {
+        self.nodes
+            .iter()
+            .filter(|node| node.mode_source == ModeSource::Default)
+            .map(|node| node.xblock.name.clone())
+            .collect()
+    }
+
+    /// Warnings about how the course is aggregated, in a stable order.
+    pub fn warnings(&self) -> Vec<AggregationWarning> {
+        self.unknown_blocktypes()
+            .into_iter()
+            .map(|blocktype| AggregationWarning::UnknownBlockType { blocktype })
+            .collect()
+    }
+
+    /// Whether a block takes a completion of its own in this course.  That
+    /// includes optional blocks without children.
+    pub fn is_completable(&self, block: &UsageKey) -> bool {
+        self.leaves.contains_key(block)
+    }
+
+    /// Whether a block is part of this course.
+    pub fn contains(&self, block: &UsageKey) -> bool {
+        self.indices.contains_key(block)
+    }
+
+    /// The key of the course's root block.
+    pub fn root(&self) -> &UsageKey {
+        &self.nodes[self.nodes.len() - 1].blockkey
+    }
+
+    /// Recompute only the aggregators affected by a change to one block
+    /// completion.
+    ///
+    /// `previous` is the user's output from `aggregate` before the change,
+    /// and `completions` holds the user's block completions.  The changed
+    /// completion takes precedence over any entry for the same block in
+    /// `completions`.  Every aggregator with the changed block as a
+    /// descendant, through any parent, is returned in post-order.  Nothing
+    /// is returned if the block isn't a completable block of this course.
+    ///
+    /// An additive aggregator is updated from its previous score by the
+    /// change in its inputs.  Only non-additive aggregators rescore their
+    /// inputs, looking up completions one block at a time, so the work done
+    /// depends on the affected aggregators rather than on the whole course.
+    pub fn reaggregate(
+        &self,
+        previous: &[Aggregator],
+        changed: &BlockCompletion,
+        completions: &BTreeMap<(User, UsageKey), BlockCompletion>,
+    ) -> Vec<Aggregator> {
+        let user = &changed.user;
+        let start = match self.indices.get(&changed.block_key) {
+            Some(&index) if self.nodes[index].leaf.is_some() => index,
+            _ => return Vec::new(),
+        };
+        let mut affected = BTreeSet::new();
+        let mut stack = vec![start];
+        while let Some(index) = stack.pop() {
+            for &dependent in &self.dependents[index] {
+                if affected.insert(dependent) {
+                    stack.push(dependent);
+                }
+            }
+        }
+
+        // The previous scores needed: the affected aggregators, and the
+        // aggregator inputs of any that are rescored.
+        let mut needed: BTreeMap<&UsageKey, usize> = BTreeMap::new();
+        for &index in &affected {
+            let node = &self.nodes[index];
+            needed.insert(&node.blockkey, index);
+            if !node.strategy.is_additive() {
+                for &input in &node.inputs {
+                    if self.nodes[input].leaf.is_none() {
+                        needed.insert(&self.nodes[input].blockkey, input);
+                    }
+                }
+            }
+        }
+        let previous: BTreeMap<usize, Score> = previous
+            .iter()
+            .filter(|agg| &agg.user == user)
+            .filter_map(|agg| {
+                needed
+                    .get(&agg.block_key)
+                    .map(|&index| (index, Score::new(agg.earned, agg.possible)))
+            })
+            .collect();
+        let leaf_score = |index: usize| {
+            let node = &self.nodes[index];
+            let completion = completions
+                .get(&(user.clone(), node.blockkey.clone()))
+                .map_or(0.0, |bc| bc.completion);
+            node.leaf_score(completion)
+        };
+
+        // The score of each changed node before and after the change.
+        // Affected nodes are visited in post-order, so a changed input is
+        // always updated before the nodes that depend on it.
+        let mut changes: BTreeMap<usize, (Score, Score)> = BTreeMap::new();
+        changes.insert(
+            start,
+            (leaf_score(start), self.nodes[start].leaf_score(changed.completion)),
+        );
+        let mut inputs: Vec<Score> = Vec::new();
+        for &index in &affected {
+            let node = &self.nodes[index];
+            let old = previous.get(&index).cloned();
+            let new = match old {
+                Some(old) if node.strategy.is_additive() => node
+                    .inputs
+                    .iter()
+                    .filter_map(|input| changes.get(input))
+                    .fold(old, |score, (before, after)| {
+                        Score::new(
+                            score.earned + after.earned - before.earned,
+                            score.possible + after.possible - before.possible,
+                        )
+                    }),
+                _ => {
+                    inputs.clear();
+                    for &input in &node.inputs {
+                        let score = match changes.get(&input) {
+                            Some(&(_, after)) => after,
+                            None if self.nodes[input].leaf.is_some() => leaf_score(input),
+                            None => previous.get(&input).cloned().unwrap_or_default(),
+                        };
+                        inputs.push(score);
+                    }
+                    node.strategy.combine(&inputs)
+                }
+            };
+            changes.insert(index, (old.unwrap_or_default(), new));
+        }
+        changes.remove(&start);
+        changes
+            .iter()
+            .filter(|(_, (_, score))| self.is_reported(score))
+            .map(|(&index, (_, score))| self.nodes[index].aggregator(user, score))
+            .collect()
     }
 
     pub fn coursekey(&self) -> &CourseKey {
         &self.coursekey
     }
+
+    /// Whether a block with this score belongs in aggregation output.
+    fn is_reported(&self, score: &Score) -> bool {
+        !(self.omit_not_applicable && score.status() == CompletionStatus::NotApplicable)
+    }
+
+    /// Score every node from a completion vector, by node index.  Nodes are
+    /// in post-order, so each node's inputs are scored before the node
+    /// itself.  Leaves missing from `completions` are scored as incomplete.
+    fn scores(&self, completions: &[f64]) -> Vec<Score> {
+        let mut scores: Vec<Score> = Vec::with_capacity(self.nodes.len());
+        let mut inputs: Vec<Score> = Vec::new();
+        for node in &self.nodes {
+            let score = match (node.xblock.mode, node.leaf) {
+                (CompletionMode::Excluded, _) => Score::default(),
+                (_, Some(leaf)) => {
+                    let completion = completions.get(leaf).cloned().unwrap_or(0.0);
+                    node.leaf_score(completion)
+                }
+                (_, None) => {
+                    inputs.clear();
+                    inputs.extend(node.inputs.iter().map(|&input| scores[input]));
+                    node.strategy.combine(&inputs)
+                }
+            };
+            scores.push(score);
+        }
+        scores
+    }
 }
 
 #[derive(Debug)]
 struct CourseNode {
     xblock: XBlock,
+    mode_source: ModeSource,
     blockkey: UsageKey,
-    children: Vec<CourseNode>,
+    /// Indices of this node's children, in course order.
+    children: Vec<usize>,
+    /// The leaf index of this block, if it is completable.
+    leaf: Option<usize>,
+    /// The weight of this block, if it has a leaf index.
+    weight: f64,
+    threshold: Option<Threshold>,
+    strategy: Arc<dyn AggregationStrategy>,
+    /// Indices of the nodes this node's strategy combines.
+    inputs: Vec<usize>,
+    /// Indices of the nodes this node contributes to an additive parent:
+    /// itself, unless it is an additive aggregator, in which case its inputs
+    /// are passed through instead.  Excluded and optional nodes contribute
+    /// nothing.
+    units: BTreeSet<usize>,
 }
 
 impl CourseNode {
-    fn new(
-        blockkey: UsageKey,
-        structure: &BTreeMap<UsageKey, Vec<UsageKey>>,
-        xblock_modes: &BTreeMap<String, CompletionMode>,
-    ) -> CourseNode {
+    /// Whether this block gets its own `Aggregator` row.  Optional leaves
+    /// don't; they are reported only in the completion tree.
+    fn is_aggregator(&self) -> bool {
+        match self.xblock.mode {
+            CompletionMode::Aggregator => true,
+            CompletionMode::Optional => self.leaf.is_none(),
+            _ => false,
+        }
+    }
+
+    /// The credit this block earns for a raw completion, before weighting.
+    fn credit(&self, completion: f64) -> f64 {
+        match self.threshold {
+            Some(threshold) => threshold.credit(completion),
+            None => completion,
+        }
+    }
+
+    /// The score of a completable block with a raw completion.
+    fn leaf_score(&self, completion: f64) -> Score {
+        Score::new(self.credit(completion) * self.weight, self.weight)
+    }
+
+    fn aggregator(&self, user: &User, score: &Score) -> Aggregator {
+        Aggregator {
+            block_key: self.blockkey.clone(),
+            user: user.clone(),
+            earned: score.earned,
+            possible: score.possible,
+        }
+    }
+}
+
+/// Flattens a checked course structure into post-ordered `CourseNode`s.
+struct CourseBuilder<'a> {
+    structure: &'a BTreeMap<UsageKey, Vec<UsageKey>>,
+    config: &'a AggregationConfig,
+    nodes: Vec<CourseNode>,
+    indices: BTreeMap<UsageKey, usize>,
+    leaf_count: usize,
+    /// For each node, the indices of the completable nodes it counts.
+    leaf_descendants: Vec<BTreeSet<usize>>,
+    /// The first problem found while adding nodes.
+    error: Option<StructureError>,
+}
+
+impl<'a> CourseBuilder<'a> {
+    /// Add `blockkey` and its descendants to `nodes` in post-order, unless it
+    /// has already been added through another parent, and return its index.
+    fn add(&mut self, blockkey: &UsageKey) -> usize {
+        if let Some(&index) = self.indices.get(blockkey) {
+            return index;
+        }
         let name = blockkey.blocktype().to_owned();
-        let mode = *xblock_modes
-            .get(&name)
-            .unwrap_or(&CompletionMode::Completable);
+        let (mode, mode_source) = self.config.mode_source(blockkey);
         let xblock = XBlock {
             name,
             mode,
             block_key: blockkey.clone(),
         };
-        let children = match structure.get(&blockkey) {
-            Some(children) => children.clone(),
-            None => Vec::new(),
+        let strategy = self.config.strategy(blockkey);
+        let structure = self.structure;
+        let structure_children = structure.get(blockkey).map(Vec::as_slice).unwrap_or(&[]);
+        let aggregating = match mode {
+            CompletionMode::Aggregator => true,
+            CompletionMode::Optional => !structure_children.is_empty(),
+            _ => false,
         };
-        let children = children
-            .iter()
-            .map(|key| CourseNode::new(key.clone(), structure, xblock_modes))
-            .collect();
-        CourseNode {
+        let mut children = Vec::new();
+        if aggregating {
+            for child in structure_children {
+                children.push(self.add(child));
+            }
+        }
+        let mut counted = Vec::new();
+        for &child in &children {
+            let mode = self.nodes[child].xblock.mode;
+            let counts = mode != CompletionMode::Excluded && mode != CompletionMode::Optional;
+            if counts && !counted.contains(&child) {
+                counted.push(child);
+            }
+        }
+        let index = self.nodes.len();
+        let has_leaf = match mode {
+            CompletionMode::Completable => true,
+            CompletionMode::Optional => !aggregating,
+            _ => false,
+        };
+        let leaf_descendants = match mode {
+            CompletionMode::Excluded => BTreeSet::new(),
+            _ if has_leaf => std::iter::once(index).collect(),
+            _ => counted
+                .iter()
+                .flat_map(|&child| self.leaf_descendants[child].iter().cloned())
+                .collect(),
+        };
+        self.leaf_descendants.push(leaf_descendants);
+        let (inputs, mut units) = match mode {
+            CompletionMode::Excluded => (Vec::new(), BTreeSet::new()),
+            _ if !aggregating => (Vec::new(), std::iter::once(index).collect()),
+            _ if strategy.is_additive() => {
+                let units: BTreeSet<usize> = counted
+                    .iter()
+                    .flat_map(|&child| self.nodes[child].units.iter().cloned())
+                    .collect();
+                let inputs: Vec<usize> = units.iter().cloned().collect();
+                self.check_shared_leaves(blockkey, &inputs);
+                (inputs, units)
+            }
+            _ => {
+                self.check_shared_leaves(blockkey, &counted);
+                (counted, std::iter::once(index).collect())
+            }
+        };
+        if mode == CompletionMode::Optional {
+            // Optional blocks are scored, but add nothing to their parents.
+            units.clear();
+        }
+        let weight = if has_leaf { self.config.weight(blockkey) } else { 0.0 };
+        let threshold = self.config.threshold(blockkey);
+        let leaf = if has_leaf {
+            self.leaf_count += 1;
+            Some(self.leaf_count - 1)
+        } else {
+            None
+        };
+        self.nodes.push(CourseNode {
             xblock,
-            blockkey,
+            mode_source,
+            blockkey: blockkey.clone(),
             children,
-        }
+            leaf,
+            weight,
+            threshold,
+            strategy,
+            inputs,
+            units,
+        });
+        self.indices.insert(blockkey.clone(), index);
+        index
     }
 
-    fn aggregate(
-        &self,
-        user: &User,
-        completions: &BTreeMap<(User, UsageKey), BlockCompletion>,
-    ) -> (Vec<Aggregator>, (f64, f64)) {
-        match self.xblock.mode {
-            CompletionMode::Excluded => (vec![], (0.0, 0.0)),
-            CompletionMode::Completable => {
-                // I want to be able to use a borrowed key, but I can only borrow the tuple, but not the elements inside it.
-                (
-                    vec![],
-                    (
-                        completions
-                            .get(&(user.clone(), self.blockkey.clone()))
-                            .map(|bc| bc.completion)
-                            .unwrap_or(0.0),
-                        1.0,
-                    ),
-                )
-            }
-            CompletionMode::Aggregator => {
-                let mut combined_aggs = vec![];
-                let mut earned = 0.0;
-                let mut possible = 0.0;
-
-                for child in &self.children {
-                    let (mut aggs, (child_earned, child_possible)) =
-                        child.aggregate(user, completions);
-                    earned += child_earned;
-                    possible += child_possible;
-                    combined_aggs.append(&mut aggs);
+    /// Record an error if the inputs of an aggregator share a completable
+    /// block, which would then be counted once for each input it is reached
+    /// through.  The inputs of an additive aggregator are distinct units, so
+    /// they can only share a block through a non-additive aggregator.
+    fn check_shared_leaves(&mut self, blockkey: &UsageKey, inputs: &[usize]) {
+        if self.error.is_some() {
+            return;
+        }
+        let mut seen = BTreeSet::new();
+        for &input in inputs {
+            for &leaf in &self.leaf_descendants[input] {
+                if !seen.insert(leaf) {
+                    self.error = Some(StructureError::SharedNonAdditiveBlock {
+                        aggregator: blockkey.clone(),
+                        block: self.nodes[leaf].blockkey.clone(),
+                    });
+                    return;
                 }
-                combined_aggs.push(Aggregator {
-                    block_key: self.blockkey.clone(),
-                    user: user.clone(),
-                    earned,
-                    possible,
+            }
+        }
+    }
+}
+
+/// Every block in the structure, whether it appears as a parent or a child.
+fn all_blocks(structure: &BTreeMap<UsageKey, Vec<UsageKey>>) -> BTreeSet<&UsageKey> {
+    structure
+        .iter()
+        .flat_map(|(parent, children)| std::iter::once(parent).chain(children))
+        .collect()
+}
+
+/// Return every block reachable from `root`, or an error if a cycle or a
+/// block from another course is found along the way.
+fn check_reachable<'a>(
+    root: &'a UsageKey,
+    structure: &'a BTreeMap<UsageKey, Vec<UsageKey>>,
+) -> Result<BTreeSet<&'a UsageKey>, StructureError> {
+    fn walk<'a>(
+        block: &'a UsageKey,
+        structure: &'a BTreeMap<UsageKey, Vec<UsageKey>>,
+        path: &mut Vec<&'a UsageKey>,
+        visited: &mut BTreeSet<&'a UsageKey>,
+    ) -> Result<(), StructureError> {
+        if let Some(position) = path.iter().position(|key| *key == block) {
+            let mut cycle: Vec<UsageKey> = path[position..].iter().map(|&key| key.clone()).collect();
+            cycle.push(block.clone());
+            return Err(StructureError::Cycle(cycle));
+        }
+        if !visited.insert(block) {
+            return Ok(());
+        }
+        path.push(block);
+        for child in structure.get(block).map(Vec::as_slice).unwrap_or(&[]) {
+            if child.course_key() != block.course_key() {
+                return Err(StructureError::ForeignBlock {
+                    parent: block.clone(),
+                    child: child.clone(),
                 });
-                (combined_aggs, (earned, possible))
             }
+            walk(child, structure, path, visited)?;
         }
+        path.pop();
+        Ok(())
     }
+
+    let mut visited = BTreeSet::new();
+    walk(root, structure, &mut Vec::new(), &mut visited)?;
+    Ok(visited)
 }
@@ -1,17 +1,30 @@
 #[macro_use]
 extern crate mysql;
 
+use std::collections::{BTreeMap, BTreeSet};
+
 use opaquekeys::{CourseKey, UsageKey};
 use serde_derive::{Serialize};
 
-use crate::aggregator::Course;
+use crate::adapters::xblock::BuiltinXBlockModeAdapter;
+use crate::aggregator::{
+    AggregationConfig, AggregationWarning, CompletionTree, Course, EffectiveMode, Remaining,
+    StructureError,
+};
 use crate::ports::blockcompletions::BlockCompletionService;
 use crate::ports::course::CourseService;
-use crate::ports::enrollment::EnrollmentService;
+use crate::ports::enrollment::{EnrollmentQuery, EnrollmentService};
+use crate::ports::xblock::XBlockModeService;
+use crate::ports::ServiceError;
+use crate::statistics::{course_statistics, CourseStatistics};
+use crate::validation::{validate_structure_with_service, StructureReport};
 
 pub mod adapters;
 pub mod aggregator;
 pub mod ports;
+pub mod statistics;
+pub mod strategy;
+pub mod validation;
 pub mod xblock;
 
 #[derive(Clone, Debug, Eq, Ord, PartialEq, PartialOrd, Serialize)]
@@ -38,7 +51,32 @@ pub struct BlockCompletion {
     pub completion: f64,
 }
 
-#[derive(Clone, Debug, PartialEq, Serialize)]
+/// Where a user stands on a block.
+#[derive(Clone, Copy, Debug, Eq, PartialEq, Serialize)]
+#[serde(rename_all = "snake_case")]
+pub enum CompletionStatus {
+    /// The block has nothing to complete, such as a chapter of discussions.
+    NotApplicable,
+    NotStarted,
+    InProgress,
+    Complete,
+}
+
+impl CompletionStatus {
+    pub fn from_scores(earned: f64, possible: f64) -> CompletionStatus {
+        if possible == 0.0 {
+            CompletionStatus::NotApplicable
+        } else if earned <= 0.0 {
+            CompletionStatus::NotStarted
+        } else if earned >= possible {
+            CompletionStatus::Complete
+        } else {
+            CompletionStatus::InProgress
+        }
+    }
+}
+
+#[derive(Clone, Debug, PartialEq)]
 pub struct Aggregator {
     pub user: User,
     pub block_key: UsageKey,
@@ -47,24 +85,171 @@ pub struct Aggregator {
 }
 
 impl Aggregator {
-    pub fn percent(&self) -> f64 {
+    /// The fraction of the block the user has completed, or `None` if the
+    /// block has nothing to complete.
+    pub fn percent(&self) -> Option<f64> {
         if self.possible == 0.0 {
-            0.0
+            None
         } else {
-            self.earned / self.possible
+            Some(self.earned / self.possible)
+        }
+    }
+
+    pub fn status(&self) -> CompletionStatus {
+        CompletionStatus::from_scores(self.earned, self.possible)
+    }
+}
+
+impl serde::Serialize for Aggregator {
+    fn serialize<S: serde::Serializer>(&self, s: S) -> Result<S::Ok, S::Error> {
+        use serde::ser::SerializeStruct;
+        let mut state = s.serialize_struct("Aggregator", 6)?;
+        state.serialize_field("user", &self.user)?;
+        state.serialize_field("block_key", &self.block_key)?;
+        state.serialize_field("earned", &self.earned)?;
+        state.serialize_field("possible", &self.possible)?;
+        state.serialize_field("percent", &self.percent())?;
+        state.serialize_field("status", &self.status())?;
+        state.end()
+    }
+}
+
+/// A user's aggregated completion, with any warnings about how the course
+/// was aggregated.
+#[derive(Clone, Debug, PartialEq, Serialize)]
+pub struct UserCompletion {
+    pub aggregators: Vec<Aggregator>,
+    pub warnings: Vec<AggregationWarning>,
+}
+
+/// Errors returned by the primary ports on `App`.
+///
+/// A wrapped service or structure error is not repeated in the message; it
+/// is available through `Error::source`.
+#[derive(Debug)]
+pub enum AppError {
+    /// The user is not enrolled in the requested course.
+    NotEnrolled,
+    /// The course service has no course with the requested key.
+    CourseNotFound,
+    /// A submitted completion is for a block that isn't a completable block
+    /// of its course.
+    BlockNotCompletable(UsageKey),
+    /// A submitted completion is not between 0 and 1.
+    InvalidCompletion(f64),
+    /// The enrollment service failed.
+    Enrollment(ServiceError),
+    /// The course service failed.
+    Course(ServiceError),
+    /// The block completion service failed.
+    BlockCompletion(ServiceError),
+    /// The xblock mode service failed.
+    XBlockModes(ServiceError),
+    /// The course structure could not be aggregated.
+    Structure(StructureError),
+}
+
+impl AppError {
+    fn from_course_error(err: ServiceError) -> AppError {
+        match err {
+            ServiceError::NotFound => AppError::CourseNotFound,
+            err => AppError::Course(err),
+        }
+    }
+
+    /// The secondary port failure behind this error, if there is one.
+    pub fn service_error(&self) -> Option<&ServiceError> {
+        match self {
+            AppError::Enrollment(err)
+            | AppError::Course(err)
+            | AppError::BlockCompletion(err)
+            | AppError::XBlockModes(err) => Some(err),
+            _ => None,
+        }
+    }
+
+    /// Whether the same request might succeed if it is tried again later.
+    pub fn is_transient(&self) -> bool {
+        self.service_error().is_some_and(ServiceError::is_transient)
+    }
+}
+
+impl std::fmt::Display for AppError {
+    fn fmt(&self, f: &mut std::fmt::Formatter) -> std::fmt::Result {
+        match self {
+            AppError::NotEnrolled => write!(f, "User is not enrolled in the course"),
+            AppError::CourseNotFound => write!(f, "Course not found"),
+            AppError::BlockNotCompletable(block) => write!(f, "Block {} is not completable", block),
+            AppError::InvalidCompletion(completion) => {
+                write!(f, "Completion {} is not between 0 and 1", completion)
+            }
+            AppError::Enrollment(_) => write!(f, "Enrollment service failed"),
+            AppError::Course(_) => write!(f, "Course service failed"),
+            AppError::BlockCompletion(_) => write!(f, "Block completion service failed"),
+            AppError::XBlockModes(_) => write!(f, "XBlock mode service failed"),
+            AppError::Structure(_) => write!(f, "Invalid course structure"),
+        }
+    }
+}
+
+impl std::error::Error for AppError {
+    fn source(&self) -> Option<&(dyn std::error::Error + 'static)> {
+        match self {
+            AppError::Structure(err) => Some(err),
+            _ => self
+                .service_error()
+                .map(|err| err as &(dyn std::error::Error + 'static)),
         }
     }
 }
 
-pub struct App<B, C, E>
+/// How `App` handles enrollment checks before returning completion data.
+#[derive(Clone, Copy, Debug, Default, Eq, PartialEq)]
+pub enum EnrollmentPolicy {
+    /// Enrollment backend failures are returned as `AppError::Enrollment`,
+    /// and so are duplicate enrollments for the same user and course.
+    Strict,
+    /// Enrollment backend failures and duplicate enrollments are treated as
+    /// enrolled.  Only a definite "not enrolled" answer is rejected.
+    /// Course-wide calls, which need the list of enrolled users, still
+    /// return backend failures.
+    #[default]
+    FailOpen,
+    /// Enrollment is not checked at all.  Useful for staff and preview.
+    Skip,
+}
+
+/// Aggregate completion for many users at once, grouping their block
+/// completions in a single pass.
+fn aggregate_users(
+    course: &Course,
+    users: Vec<User>,
+    blockcompletions: &BTreeMap<(User, UsageKey), BlockCompletion>,
+) -> BTreeMap<User, Vec<Aggregator>> {
+    let vectors = course.completion_vectors(blockcompletions.values());
+    users
+        .into_iter()
+        .map(|user| {
+            let completions = vectors.get(&user).map(Vec::as_slice).unwrap_or(&[]);
+            let aggregators = course.aggregate_completions(&user, completions);
+            (user, aggregators)
+        })
+        .collect()
+}
+
+pub struct App<B, C, E, X = BuiltinXBlockModeAdapter>
 where
     B: BlockCompletionService,
     C: CourseService,
     E: EnrollmentService,
+    X: XBlockModeService,
 {
     blockcompletion_service: B,
     course_service: C,
     enrollment_service: E,
+    xblock_mode_service: X,
+    enrollment_policy: EnrollmentPolicy,
+    aggregation_config: AggregationConfig,
 }
 
 impl<B, C, E> App<B, C, E>
@@ -82,26 +267,373 @@ where
             blockcompletion_service,
             course_service,
             enrollment_service,
+            xblock_mode_service: BuiltinXBlockModeAdapter::new(),
+            enrollment_policy: EnrollmentPolicy::default(),
+            aggregation_config: AggregationConfig::default(),
+        }
+    }
+}
+
+impl<B, C, E, X> App<B, C, E, X>
+where
+    B: BlockCompletionService,
+    C: CourseService,
+    E: EnrollmentService,
+    X: XBlockModeService,
+{
+    /// Get xblock modes from `xblock_mode_service` instead of the built-in
+    /// modes.  The mode service is the only source of blocktype modes; use
+    /// course or block overrides on the aggregation config for exceptions.
+    pub fn with_xblock_mode_service<Y>(self, xblock_mode_service: Y) -> App<B, C, E, Y>
+    where
+        Y: XBlockModeService,
+    {
+        App {
+            blockcompletion_service: self.blockcompletion_service,
+            course_service: self.course_service,
+            enrollment_service: self.enrollment_service,
+            xblock_mode_service,
+            enrollment_policy: self.enrollment_policy,
+            aggregation_config: self.aggregation_config,
         }
     }
 
+    pub fn with_enrollment_policy(mut self, enrollment_policy: EnrollmentPolicy) -> App<B, C, E, X> {
+        self.enrollment_policy = enrollment_policy;
+        self
+    }
+
+    pub fn with_aggregation_config(mut self, aggregation_config: AggregationConfig) -> App<B, C, E, X> {
+        self.aggregation_config = aggregation_config;
+        self
+    }
+
     pub fn get_user_completion(
         &self,
         user: &User,
         coursekey: &CourseKey,
-    ) -> Option<Vec<Aggregator>> {
-        if self.enrollment_service
-            .is_enrolled(user, coursekey)
-            .unwrap_or(true)
-        {
-            let structure = self.course_service.get_course(coursekey).ok()?;
-            let course = Course::from_structure(&structure);
-            let blockcompletions = self.blockcompletion_service
-                .get_user_blockcompletions(user, coursekey)
-                .unwrap_or_default();
-            Some(course.aggregate(user, &blockcompletions))
-        } else {
-            None
+    ) -> Result<Vec<Aggregator>, AppError> {
+        self.get_user_completion_with_warnings(user, coursekey)
+            .map(|completion| completion.aggregators)
+    }
+
+    /// Like `get_user_completion`, but also report warnings about how the
+    /// course was aggregated, such as blocktypes with no completion mode.
+    ///
+    /// This is the only port that returns warnings.  They describe the
+    /// course rather than any one user, so the other ports leave them out.
+    /// `get_structure_report` lists the same unknown blocktypes, for a
+    /// structure that aggregates.
+    pub fn get_user_completion_with_warnings(
+        &self,
+        user: &User,
+        coursekey: &CourseKey,
+    ) -> Result<UserCompletion, AppError> {
+        self.check_enrollment(user, coursekey)?;
+        let course = self.load_course(coursekey, &self.aggregation_config()?)?;
+        let blockcompletions = self.blockcompletion_service
+            .get_user_blockcompletions(user, coursekey)
+            .map_err(AppError::BlockCompletion)?;
+        Ok(UserCompletion {
+            aggregators: course.aggregate(user, &blockcompletions),
+            warnings: course.warnings(),
+        })
+    }
+
+    /// Aggregate a user's completion into a tree that mirrors the course
+    /// outline.  Completable and excluded blocks are included only when
+    /// `include_leaves` is set.
+    pub fn get_user_completion_tree(
+        &self,
+        user: &User,
+        coursekey: &CourseKey,
+        include_leaves: bool,
+    ) -> Result<CompletionTree, AppError> {
+        self.check_enrollment(user, coursekey)?;
+        let course = self.load_course(coursekey, &self.aggregation_config()?)?;
+        let blockcompletions = self.blockcompletion_service
+            .get_user_blockcompletions(user, coursekey)
+            .map_err(AppError::BlockCompletion)?;
+        Ok(course.aggregate_tree(user, &blockcompletions, include_leaves))
+    }
+
+    /// Aggregate completion for every learner enrolled in the course.
+    ///
+    /// The course structure is fetched and built once, then reused for each
+    /// enrolled user.  Under `EnrollmentPolicy::Skip`, the learners are the
+    /// users with any block completion in the course.
+    ///
+    /// An enrollment backend failure is returned as `AppError::Enrollment`
+    /// even under `EnrollmentPolicy::FailOpen`.  There is no list of users to
+    /// fall back to, and guessing one from the block completions would
+    /// silently leave out learners who haven't started the course.
+    pub fn get_course_completion(
+        &self,
+        coursekey: &CourseKey,
+    ) -> Result<BTreeMap<User, Vec<Aggregator>>, AppError> {
+        let course = self.load_course(coursekey, &self.aggregation_config()?)?;
+        let blockcompletions = self.blockcompletion_service
+            .get_course_blockcompletions(coursekey)
+            .map_err(AppError::BlockCompletion)?;
+        let users = match self.enrollment_policy {
+            EnrollmentPolicy::Skip => blockcompletions.keys().map(|(user, _)| user.clone()).collect(),
+            _ => self.enrollment_service
+                .get_enrolled_users(coursekey)
+                .map_err(AppError::Enrollment)?
+                .into_iter()
+                .map(|enrollment| enrollment.user)
+                .collect(),
+        };
+        let enrolled = self.distinct_users(users)?;
+        Ok(aggregate_users(&course, enrolled, &blockcompletions))
+    }
+
+    /// Summarize completion of every aggregator block across all learners in
+    /// the course, with a histogram of `buckets` equal-width buckets.  The
+    /// learners are found as in `get_course_completion`.
+    pub fn get_course_statistics(
+        &self,
+        coursekey: &CourseKey,
+        buckets: usize,
+    ) -> Result<CourseStatistics, AppError> {
+        let completion = self.get_course_completion(coursekey)?;
+        Ok(course_statistics(coursekey, &completion, buckets))
+    }
+
+    /// Aggregate completion for a selected set of learners in one course.
+    ///
+    /// Users who are not enrolled in the course are left out of the result,
+    /// unless the enrollment policy says otherwise.
+    /// Block completions for the remaining users are fetched in one batch.
+    /// An empty list of users gives an empty result.
+    pub fn get_users_completion(
+        &self,
+        users: &[User],
+        coursekey: &CourseKey,
+    ) -> Result<BTreeMap<User, Vec<Aggregator>>, AppError> {
+        if users.is_empty() {
+            return Ok(BTreeMap::new());
+        }
+        let query = EnrollmentQuery::default()
+            .add_users(users)
+            .add_courses(std::slice::from_ref(coursekey));
+        let enrolled = self.enrolled_users(&query, || users.to_vec())?;
+        let course = self.load_course(coursekey, &self.aggregation_config()?)?;
+        let blockcompletions = self.blockcompletion_service
+            .get_users_blockcompletions(&enrolled, coursekey)
+            .map_err(AppError::BlockCompletion)?;
+        Ok(aggregate_users(&course, enrolled, &blockcompletions))
+    }
+
+    /// Aggregate a user's completion for a single block and its descendants,
+    /// such as one chapter or sequential of a course.
+    pub fn get_subtree_completion(
+        &self,
+        user: &User,
+        blockkey: &UsageKey,
+    ) -> Result<Vec<Aggregator>, AppError> {
+        let coursekey = blockkey.course_key();
+        self.check_enrollment(user, coursekey)?;
+        let course = self.load_subtree(blockkey, &self.aggregation_config()?)?;
+        let blockcompletions = self.blockcompletion_service
+            .get_user_blockcompletions(user, coursekey)
+            .map_err(AppError::BlockCompletion)?;
+        Ok(course.aggregate(user, &blockcompletions))
+    }
+
+    /// List the blocks a user has left to complete under a block, along with
+    /// the blocks that don't count toward its completion.
+    pub fn get_remaining_blocks(&self, user: &User, blockkey: &UsageKey) -> Result<Remaining, AppError> {
+        let coursekey = blockkey.course_key();
+        self.check_enrollment(user, coursekey)?;
+        let course = self.load_subtree(blockkey, &self.aggregation_config()?)?;
+        let blockcompletions = self.blockcompletion_service
+            .get_user_blockcompletions(user, coursekey)
+            .map_err(AppError::BlockCompletion)?;
+        course
+            .remaining(user, &blockcompletions, blockkey)
+            .ok_or_else(|| AppError::Structure(StructureError::RootNotFound(blockkey.clone())))
+    }
+
+    /// Find the block a user should resume the course at: the first
+    /// incomplete completable block in course order, optionally limited to
+    /// the blocks under `scope`, such as a chapter or sequential.  Returns
+    /// `None` when there is nothing left to complete.
+    pub fn get_resume_block(
+        &self,
+        user: &User,
+        coursekey: &CourseKey,
+        scope: Option<&UsageKey>,
+    ) -> Result<Option<UsageKey>, AppError> {
+        self.check_enrollment(user, coursekey)?;
+        let course = self.load_course(coursekey, &self.aggregation_config()?)?;
+        let scope = scope.unwrap_or_else(|| course.root());
+        if !course.contains(scope) {
+            return Err(AppError::Structure(StructureError::RootNotFound(scope.clone())));
+        }
+        let blockcompletions = self.blockcompletion_service
+            .get_user_blockcompletions(user, coursekey)
+            .map_err(AppError::BlockCompletion)?;
+        Ok(course.next_incomplete(user, &blockcompletions, scope))
+    }
+
+    /// Summarize a user's completion in every course they are enrolled in.
+    ///
+    /// Each course maps to its root `Aggregator`.  A failure to load one
+    /// course is reported against that course, and does not fail the call.
+    /// The xblock modes are loaded once, for every course.
+    pub fn get_enrolled_courses_completion(
+        &self,
+        user: &User,
+    ) -> Result<BTreeMap<CourseKey, Result<Aggregator, AppError>>, AppError> {
+        let enrollments = self.enrollment_service
+            .get_enrolled_courses(user)
+            .map_err(AppError::Enrollment)?;
+        let config = self.aggregation_config()?;
+        Ok(enrollments
+            .into_iter()
+            .map(|enrollment| {
+                let summary = self.load_course(&enrollment.course, &config).and_then(|course| {
+                    let blockcompletions = self.blockcompletion_service
+                        .get_user_blockcompletions(user, &enrollment.course)
+                        .map_err(AppError::BlockCompletion)?;
+                    Ok(course.aggregate_root(user, &blockcompletions))
+                });
+                (enrollment.course, summary)
+            })
+            .collect())
+    }
+
+    /// Record a user's completion of a block.  The user must be enrolled in
+    /// the block's course, according to the enrollment policy, and the block
+    /// must be completable.
+    ///
+    /// Checking the block fetches and builds its course on every call, so
+    /// prefer `submit_completions_batch` for many completions at once.
+    pub fn submit_completion(&self, blockcompletion: &BlockCompletion) -> Result<(), AppError> {
+        self.submit_completions_batch(std::slice::from_ref(blockcompletion))
+    }
+
+    /// Record several block completions, possibly across users and courses.
+    /// Every completion is checked as in `submit_completion` before any are
+    /// recorded.  Each course in the batch is fetched and built once, and
+    /// the xblock modes are loaded once; nothing is cached between calls, so
+    /// structure and mode changes take effect immediately.
+    pub fn submit_completions_batch(&self, blockcompletions: &[BlockCompletion]) -> Result<(), AppError> {
+        let config = self.aggregation_config()?;
+        let mut courses: BTreeMap<&CourseKey, Course> = BTreeMap::new();
+        let mut enrolled: BTreeSet<(&User, &CourseKey)> = BTreeSet::new();
+        for blockcompletion in blockcompletions {
+            if !(0.0..=1.0).contains(&blockcompletion.completion) {
+                return Err(AppError::InvalidCompletion(blockcompletion.completion));
+            }
+            let coursekey = blockcompletion.block_key.course_key();
+            if enrolled.insert((&blockcompletion.user, coursekey)) {
+                self.check_enrollment(&blockcompletion.user, coursekey)?;
+            }
+            if !courses.contains_key(coursekey) {
+                courses.insert(coursekey, self.load_course(coursekey, &config)?);
+            }
+            if !courses[coursekey].is_completable(&blockcompletion.block_key) {
+                return Err(AppError::BlockNotCompletable(blockcompletion.block_key.clone()));
+            }
+        }
+        self.blockcompletion_service
+            .submit_completions_batch(blockcompletions)
+            .map_err(AppError::BlockCompletion)
+    }
+
+    /// List the completion mode of every block in a course, and where each
+    /// mode came from.
+    pub fn get_effective_modes(&self, coursekey: &CourseKey) -> Result<Vec<EffectiveMode>, AppError> {
+        Ok(self.load_course(coursekey, &self.aggregation_config()?)?.effective_modes())
+    }
+
+    /// Report problems with a course's structure that would affect
+    /// aggregation, under the app's aggregation config.
+    pub fn get_structure_report(&self, coursekey: &CourseKey) -> Result<StructureReport, AppError> {
+        let structure = self.course_service
+            .get_course(coursekey)
+            .map_err(AppError::from_course_error)?;
+        validate_structure_with_service(&structure, &self.aggregation_config, &self.xblock_mode_service)
+            .map_err(AppError::XBlockModes)
+    }
+
+    /// Check that a user may see completion for a course, according to the
+    /// enrollment policy.
+    fn check_enrollment(&self, user: &User, coursekey: &CourseKey) -> Result<(), AppError> {
+        if self.enrollment_policy == EnrollmentPolicy::Skip {
+            return Ok(());
+        }
+        match self.enrollment_service.get_enrollment(user, coursekey) {
+            Ok(Some(_)) => Ok(()),
+            Ok(None) => Err(AppError::NotEnrolled),
+            Err(err) => match self.enrollment_policy {
+                EnrollmentPolicy::Strict => Err(AppError::Enrollment(err)),
+                _ => Ok(()),
+            },
         }
     }
+
+    /// Run an enrollment query and return the distinct enrolled users,
+    /// according to the enrollment policy.  `fallback` supplies the users to
+    /// return when enrollment is skipped, or when the enrollment backend
+    /// fails under `EnrollmentPolicy::FailOpen`.
+    fn enrolled_users<F>(&self, query: &EnrollmentQuery, fallback: F) -> Result<Vec<User>, AppError>
+    where
+        F: FnOnce() -> Vec<User>,
+    {
+        let users = match self.enrollment_policy {
+            EnrollmentPolicy::Skip => fallback(),
+            policy => match self.enrollment_service.query_enrollment(query) {
+                Ok(enrollments) => enrollments
+                    .into_iter()
+                    .map(|enrollment| enrollment.user)
+                    .collect(),
+                Err(err) => {
+                    if policy == EnrollmentPolicy::Strict {
+                        return Err(AppError::Enrollment(err));
+                    }
+                    fallback()
+                }
+            },
+        };
+        self.distinct_users(users)
+    }
+
+    /// Sort and deduplicate users.  Under `EnrollmentPolicy::Strict`, a user
+    /// listed twice is a duplicate enrollment, and an error.
+    fn distinct_users(&self, mut users: Vec<User>) -> Result<Vec<User>, AppError> {
+        let len = users.len();
+        users.sort();
+        users.dedup();
+        if self.enrollment_policy == EnrollmentPolicy::Strict && users.len() != len {
+            return Err(AppError::Enrollment(ServiceError::MultipleResults));
+        }
+        Ok(users)
+    }
+
+    fn load_course(&self, coursekey: &CourseKey, config: &AggregationConfig) -> Result<Course, AppError> {
+        let structure = self.course_service
+            .get_course(coursekey)
+            .map_err(AppError::from_course_error)?;
+        Course::from_structure(&structure, config).map_err(AppError::Structure)
+    }
+
+    fn load_subtree(&self, blockkey: &UsageKey, config: &AggregationConfig) -> Result<Course, AppError> {
+        let structure = self.course_service
+            .get_course(blockkey.course_key())
+            .map_err(AppError::from_course_error)?;
+        Course::from_root(blockkey, &structure, config).map_err(AppError::Structure)
+    }
+
+    /// The aggregation config, with the modes from the xblock mode service.
+    /// Each public method calls this at most once, so a mode service that
+    /// reads a file reads it once per call.
+    fn aggregation_config(&self) -> Result<AggregationConfig, AppError> {
+        let xblock_modes = self.xblock_mode_service
+            .get_xblock_modes()
+            .map_err(AppError::XBlockModes)?;
+        Ok(self.aggregation_config.clone().with_xblock_modes(xblock_modes))
+    }
 }
@@ -16,4 +16,24 @@ pub trait BlockCompletionService {
         user: &User,
         coursekey: &CourseKey,
     ) -> Result<BTreeMap<(User, UsageKey), BlockCompletion>>;
+
+    /// Fetch block completions for several users in a course in a single call.
+    fn get_users_blockcompletions(
+        &self,
+        users: &[User],
+        coursekey: &CourseKey,
+    ) -> Result<BTreeMap<(User, UsageKey), BlockCompletion>>;
+
+    /// Record a user's completion of a block, replacing any completion
+    /// already recorded for the same user and block.
+    fn submit_completion(&self, blockcompletion: &BlockCompletion) -> Result<()>;
+
+    /// Record several block completions.  Adapters that can write them all
+    /// at once should; by default they are submitted one at a time.
+    fn submit_completions_batch(&self, blockcompletions: &[BlockCompletion]) -> Result<()> {
+        for blockcompletion in blockcompletions {
+            self.submit_completion(blockcompletion)?;
+        }
+        Ok(())
+    }
 }
@@ -1,20 +1,71 @@
 use std::error::Error;
+use std::time::Duration;
 
+/// Failures of the secondary ports.
+///
+/// A wrapped error is not repeated in the message; it is available through
+/// `Error::source`.  `Other` is the exception: it stands in for the wrapped
+/// error, showing its message and passing on its source.
 #[derive(Debug)]
 pub enum ServiceError {
     NotFound,
     MultipleResults,
-    Other(Box<Error>),
+    /// The backend did not answer in time.
+    Timeout(Box<dyn Error>),
+    /// The backend could not be reached, or is failing.
+    Unavailable(Box<dyn Error>),
+    /// The backend rejected our credentials.
+    Authentication(Box<dyn Error>),
+    /// The backend answered with data we couldn't use, such as a malformed
+    /// key in a database row.
+    InvalidData(Box<dyn Error>),
+    /// The backend asked us to slow down, and when to try again if it said.
+    RateLimited { retry_after: Option<Duration> },
+    Other(Box<dyn Error>),
 }
 
 impl ServiceError {
     pub fn from_error<E: 'static + Error>(err: E) -> ServiceError {
         ServiceError::Other(Box::new(err))
     }
+
+    /// Whether the same request might succeed if it is tried again later.
+    pub fn is_transient(&self) -> bool {
+        matches!(
+            self,
+            ServiceError::Timeout(_) | ServiceError::Unavailable(_) | ServiceError::RateLimited { .. }
+        )
+    }
 }
+
 impl std::fmt::Display for ServiceError {
     fn fmt(&self, f: &mut std::fmt::Formatter) -> std::fmt::Result {
-        write!(f, "ServiceError: {:?}", self)
+        match self {
+            ServiceError::NotFound => write!(f, "Not found"),
+            ServiceError::MultipleResults => write!(f, "Multiple results found where one was expected"),
+            ServiceError::Timeout(_) => write!(f, "Timed out"),
+            ServiceError::Unavailable(_) => write!(f, "Unavailable"),
+            ServiceError::Authentication(_) => write!(f, "Authentication failed"),
+            ServiceError::InvalidData(_) => write!(f, "Invalid data"),
+            ServiceError::RateLimited {
+                retry_after: Some(retry_after),
+            } => write!(f, "Rate limited, retry after {}s", retry_after.as_secs()),
+            ServiceError::RateLimited { retry_after: None } => write!(f, "Rate limited"),
+            ServiceError::Other(err) => write!(f, "{}", err),
+        }
+    }
+}
+
+impl Error for ServiceError {
+    fn source(&self) -> Option<&(dyn Error + 'static)> {
+        match self {
+            ServiceError::Timeout(err)
+            | ServiceError::Unavailable(err)
+            | ServiceError::Authentication(err)
+            | ServiceError::InvalidData(err) => Some(&**err),
+            ServiceError::Other(err) => err.source(),
+            _ => None,
+        }
     }
 }
 
@@ -23,3 +74,4 @@ pub type Result<T> = std::result::Result<T, ServiceError>;
 pub mod blockcompletions;
 pub mod course;
 pub mod enrollment;
+pub mod xblock;
@@ -0,0 +1,12 @@
+use std::collections::BTreeMap;
+
+use crate::xblock::CompletionMode;
+
+use super::Result;
+
+pub trait XBlockModeService {
+    /// Returns a BTreeMap mapping each known xblock type to its completion mode.
+    /// Blocks of unknown types are aggregated according to the
+    /// `UnknownBlockTypePolicy` in the app's aggregation config.
+    fn get_xblock_modes(&self) -> Result<BTreeMap<String, CompletionMode>>;
+}
@@ -0,0 +1,114 @@
+use std::collections::BTreeMap;
+
+use opaquekeys::{CourseKey, UsageKey};
+use serde_derive::Serialize;
+
+use crate::{Aggregator, CompletionStatus, User};
+
+/// Completion of one aggregator block, summarized across learners.
+#[derive(Clone, Debug, PartialEq, Serialize)]
+pub struct BlockStatistics {
+    pub block_key: UsageKey,
+    /// `None` if the block has nothing to complete for any learner.
+    pub mean_percent: Option<f64>,
+    pub median_percent: Option<f64>,
+    /// Learners for whom the block has nothing to complete.  They are left
+    /// out of the percentages and the histogram.
+    pub not_applicable: usize,
+    /// Learners with nothing earned in the block.
+    pub not_started: usize,
+    /// Learners with something, but not everything, earned in the block.
+    pub in_progress: usize,
+    /// Learners who have completed the block.
+    pub complete: usize,
+    /// Learner counts in equal-width percent buckets, from 0% upward.  The
+    /// last bucket includes 100%.
+    pub histogram: Vec<usize>,
+}
+
+/// Completion of every aggregator block in a course, summarized across
+/// learners.
+#[derive(Clone, Debug, PartialEq, Serialize)]
+pub struct CourseStatistics {
+    pub course_key: CourseKey,
+    pub users: usize,
+    /// One entry per aggregator block, in post-order.
+    pub blocks: Vec<BlockStatistics>,
+}
+
+/// Summarize course-wide aggregation output, as returned by
+/// `App::get_course_completion`, into per-block statistics with a histogram
+/// of `buckets` buckets.
+pub fn course_statistics(
+    coursekey: &CourseKey,
+    completion: &BTreeMap<User, Vec<Aggregator>>,
+    buckets: usize,
+) -> CourseStatistics {
+    let mut order: Vec<&UsageKey> = Vec::new();
+    let mut by_block: BTreeMap<&UsageKey, Vec<&Aggregator>> = BTreeMap::new();
+    for aggregators in completion.values() {
+        for agg in aggregators {
+            let entry = by_block.entry(&agg.block_key).or_insert_with(|| {
+                order.push(&agg.block_key);
+                Vec::new()
+            });
+            entry.push(agg);
+        }
+    }
+    CourseStatistics {
+        course_key: coursekey.clone(),
+        users: completion.len(),
+        blocks: order
+            .into_iter()
+            .map(|block_key| block_statistics(block_key, &by_block[block_key], buckets))
+            .collect(),
+    }
+}
+
+fn block_statistics(block_key: &UsageKey, aggregators: &[&Aggregator], buckets: usize) -> BlockStatistics {
+    let mut percents: Vec<f64> = aggregators.iter().filter_map(|agg| agg.percent()).collect();
+    percents.sort_by(|a, b| a.partial_cmp(b).unwrap_or(std::cmp::Ordering::Equal));
+
+    let mean_percent = if percents.is_empty() {
+        None
+    } else {
+        Some(percents.iter().sum::<f64>() / percents.len() as f64)
+    };
+    let median_percent = match percents.len() {
+        0 => None,
+        len if len % 2 == 1 => Some(percents[len / 2]),
+        len => Some((percents[len / 2 - 1] + percents[len / 2]) / 2.0),
+    };
+
+    let mut not_applicable = 0;
+    let mut not_started = 0;
+    let mut in_progress = 0;
+    let mut complete = 0;
+    for agg in aggregators {
+        match agg.status() {
+            CompletionStatus::NotApplicable => not_applicable += 1,
+            CompletionStatus::NotStarted => not_started += 1,
+            CompletionStatus::InProgress => in_progress += 1,
+            CompletionStatus::Complete => complete += 1,
+        }
+    }
+
+    let mut histogram = vec![0; buckets];
+    if buckets > 0 {
+        for percent in &percents {
+            let bucket = (percent * buckets as f64).floor() as usize;
+            histogram[bucket.min(buckets - 1)] += 1;
+        }
+    }
+
+    BlockStatistics {
+        block_key: block_key.clone(),
+        mean_percent,
+        median_percent,
+        not_applicable,
+        not_started,
+        in_progress,
+        complete,
+        histogram,
+    }
+}
@@ -0,0 +1,127 @@
+use crate::CompletionStatus;
+
+/// The earned and possible completion of a block.
+#[derive(Clone, Copy, Debug, Default, PartialEq)]
+pub struct Score {
+    pub earned: f64,
+    pub possible: f64,
+}
+
+impl Score {
+    pub fn new(earned: f64, possible: f64) -> Score {
+        Score { earned, possible }
+    }
+
+    pub fn percent(&self) -> f64 {
+        if self.possible == 0.0 {
+            0.0
+        } else {
+            self.earned / self.possible
+        }
+    }
+
+    pub fn status(&self) -> CompletionStatus {
+        CompletionStatus::from_scores(self.earned, self.possible)
+    }
+
+    pub fn is_complete(&self) -> bool {
+        self.earned >= self.possible
+    }
+}
+
+/// Combines the scores of an aggregator block's inputs into the block's own
+/// score.
+pub trait AggregationStrategy: std::fmt::Debug + Send + Sync {
+    fn combine(&self, inputs: &[Score]) -> Score;
+
+    /// Whether the strategy simply adds up its inputs.
+    ///
+    /// An additive aggregator's inputs are the distinct blocks reached
+    /// through it and any additive aggregators below it, rather than its
+    /// direct children.  That way a block shared between two children (in a
+    /// DAG-shaped course) is only counted once.  Non-additive aggregators
+    /// always see their direct children, so a course where a block would
+    /// still be counted twice (two children of a non-additive aggregator
+    /// sharing it, or two non-additive aggregators sharing it under one
+    /// additive aggregator) is rejected with
+    /// `StructureError::SharedNonAdditiveBlock`.
+    fn is_additive(&self) -> bool {
+        false
+    }
+}
+
+/// Sum the earned and possible completion of every input.  This is the
+/// default strategy.
+#[derive(Clone, Copy, Debug, Default)]
+pub struct Sum;
+
+impl AggregationStrategy for Sum {
+    fn combine(&self, inputs: &[Score]) -> Score {
+        inputs.iter().fold(Score::default(), |total, input| {
+            Score::new(total.earned + input.earned, total.possible + input.possible)
+        })
+    }
+
+    fn is_additive(&self) -> bool {
+        true
+    }
+}
+
+/// Earn full credit only once every input is complete, and nothing before.
+#[derive(Clone, Copy, Debug, Default)]
+pub struct AllOrNothing;
+
+impl AggregationStrategy for AllOrNothing {
+    fn combine(&self, inputs: &[Score]) -> Score {
+        let total = Sum.combine(inputs);
+        if inputs.iter().all(Score::is_complete) {
+            Score::new(total.possible, total.possible)
+        } else {
+            Score::new(0.0, total.possible)
+        }
+    }
+}
+
+/// Take the mean of the inputs' percentages, so that each input counts
+/// equally regardless of its size.  `possible` is still the sum of the
+/// inputs, so the block keeps its size in its own parent.
+#[derive(Clone, Copy, Debug, Default)]
+pub struct MeanPercent;
+
+impl AggregationStrategy for MeanPercent {
+    fn combine(&self, inputs: &[Score]) -> Score {
+        let total = Sum.combine(inputs);
+        let counted: Vec<f64> = inputs
+            .iter()
+            .filter(|input| input.possible > 0.0)
+            .map(Score::percent)
+            .collect();
+        if counted.is_empty() {
+            return total;
+        }
+        let mean = counted.iter().sum::<f64>() / counted.len() as f64;
+        Score::new(mean * total.possible, total.possible)
+    }
+}
+
+/// Take the lowest of the inputs' percentages.  `possible` is the sum of the
+/// inputs.
+#[derive(Clone, Copy, Debug, Default)]
+pub struct Minimum;
+
+impl AggregationStrategy for Minimum {
+    fn combine(&self, inputs: &[Score]) -> Score {
+        let total = Sum.combine(inputs);
+        let minimum = inputs
+            .iter()
+            .filter(|input| input.possible > 0.0)
+            .map(Score::percent)
+            .fold(None, |minimum: Option<f64>, percent| {
+                Some(minimum.map_or(percent, |minimum| minimum.min(percent)))
+            });
+        match minimum {
+            Some(minimum) => Score::new(minimum * total.possible, total.possible),
+            None => total,
+        }
+    }
+}
@@ -0,0 +1,188 @@
+use std::collections::{BTreeMap, BTreeSet};
+
+use opaquekeys::UsageKey;
+
+use crate::aggregator::{AggregationConfig, Course, ModeSource, StructureError};
+use crate::ports;
+use crate::ports::xblock::XBlockModeService;
+use crate::xblock::CompletionMode;
+
+/// Diagnostic report on a course structure, as returned by
+/// `CourseService::get_course`.
+///
+/// Unlike `Course::from_structure`, building a report never fails.  Any
+/// error that would prevent aggregation is recorded in `error`, and the rest
+/// of the report is still filled in as far as possible.
+#[derive(Clone, Debug, PartialEq)]
+pub struct StructureReport {
+    /// The `course` block, if one was found.
+    pub root: Option<UsageKey>,
+    /// Why the structure can't be aggregated, if it can't.
+    pub error: Option<StructureError>,
+    /// Blocks that can't be reached from the course root.
+    pub unreachable_blocks: Vec<UsageKey>,
+    /// Blocktypes with no completion mode, in the xblock modes or in a
+    /// course or block override.  The unknown blocktype policy decides how
+    /// they are aggregated.  Only the blocks aggregation would include are
+    /// checked: the root, and the children of included blocks that
+    /// aggregate.  So this matches `Course::unknown_blocktypes`.
+    pub unknown_blocktypes: BTreeSet<String>,
+    /// Aggregator blocks with no completable descendants.
+    pub empty_aggregators: Vec<UsageKey>,
+    /// The number of levels below the course root.
+    pub max_depth: usize,
+}
+
+impl StructureReport {
+    /// True if the report found nothing worth fixing.
+    pub fn is_clean(&self) -> bool {
+        self.error.is_none()
+            && self.unreachable_blocks.is_empty()
+            && self.unknown_blocktypes.is_empty()
+            && self.empty_aggregators.is_empty()
+    }
+}
+
+/// Build a `StructureReport` for a course structure, with block modes and
+/// the structure error resolved through `config`, as aggregation would.
+pub fn validate_structure(
+    structure: &BTreeMap<UsageKey, Vec<UsageKey>>,
+    config: &AggregationConfig,
+) -> StructureReport {
+    let blocks: BTreeSet<&UsageKey> = structure
+        .iter()
+        .flat_map(|(parent, children)| std::iter::once(parent).chain(children))
+        .collect();
+    let root = blocks
+        .iter()
+        .find(|block| block.blocktype() == "course")
+        .map(|&block| block.clone());
+    let unknown_blocktypes = match root {
+        Some(ref root) => unknown_blocktypes(structure, config, root),
+        None => BTreeSet::new(),
+    };
+
+    let mut walker = Walker {
+        structure,
+        config,
+        path: BTreeSet::new(),
+        visited: BTreeMap::new(),
+    };
+    let max_depth = match root {
+        Some(ref root) => walker.visit(root).height,
+        None => 0,
+    };
+    let unreachable_blocks = blocks
+        .iter()
+        .filter(|block| !walker.visited.contains_key(*block))
+        .map(|&block| block.clone())
+        .collect();
+    let empty_aggregators = walker
+        .visited
+        .iter()
+        .filter(|(block, _)| walker.mode(block) == CompletionMode::Aggregator)
+        .filter(|(_, visit)| !visit.has_completable)
+        .map(|(&block, _)| block.clone())
+        .collect();
+
+    StructureReport {
+        root,
+        error: Course::from_structure(structure, config).err(),
+        unreachable_blocks,
+        unknown_blocktypes,
+        empty_aggregators,
+        max_depth,
+    }
+}
+
+/// Like `validate_structure`, but with the blocktype modes served by
+/// `xblock_mode_service` in place of the ones in `config`, as an `App` with
+/// that service would aggregate.
+pub fn validate_structure_with_service<X: XBlockModeService>(
+    structure: &BTreeMap<UsageKey, Vec<UsageKey>>,
+    config: &AggregationConfig,
+    xblock_mode_service: &X,
+) -> ports::Result<StructureReport> {
+    let xblock_modes = xblock_mode_service.get_xblock_modes()?;
+    let config = config.clone().with_xblock_modes(xblock_modes);
+    Ok(validate_structure(structure, &config))
+}
+
+/// The blocktypes with no completion mode among the blocks `Course` would
+/// include.  Like `CourseBuilder`, this only descends into blocks that
+/// aggregate their children.
+fn unknown_blocktypes(
+    structure: &BTreeMap<UsageKey, Vec<UsageKey>>,
+    config: &AggregationConfig,
+    root: &UsageKey,
+) -> BTreeSet<String> {
+    let mut unknown = BTreeSet::new();
+    let mut seen = BTreeSet::new();
+    let mut stack = vec![root];
+    while let Some(block) = stack.pop() {
+        if !seen.insert(block) {
+            continue;
+        }
+        let (mode, source) = config.mode_source(block);
+        if source == ModeSource::Default {
+            unknown.insert(block.blocktype().to_owned());
+        }
+        let children = structure.get(block).map(Vec::as_slice).unwrap_or(&[]);
+        let aggregating = match mode {
+            CompletionMode::Aggregator => true,
+            CompletionMode::Optional => !children.is_empty(),
+            _ => false,
+        };
+        if aggregating {
+            stack.extend(children);
+        }
+    }
+    unknown
+}
+
+#[derive(Clone, Copy)]
+struct Visit {
+    height: usize,
+    has_completable: bool,
+}
+
+/// Depth-first walk over every edge reachable from the root.  Edges that
+/// would close a cycle are skipped, so the walk always terminates.
+struct Walker<'a> {
+    structure: &'a BTreeMap<UsageKey, Vec<UsageKey>>,
+    config: &'a AggregationConfig,
+    path: BTreeSet<&'a UsageKey>,
+    visited: BTreeMap<&'a UsageKey, Visit>,
+}
+
+impl<'a> Walker<'a> {
+    fn mode(&self, block: &UsageKey) -> CompletionMode {
+        self.config.mode(block)
+    }
+
+    fn visit(&mut self, block: &'a UsageKey) -> Visit {
+        if let Some(&visit) = self.visited.get(block) {
+            return visit;
+        }
+        let mode = self.mode(block);
+        let mut visit = Visit {
+            height: 0,
+            has_completable: mode == CompletionMode::Completable,
+        };
+        self.path.insert(block);
+        let structure = self.structure;
+        for child in structure.get(block).map(Vec::as_slice).unwrap_or(&[]) {
+            if self.path.contains(child) {
+                continue;
+            }
+            let child_visit = self.visit(child);
+            visit.height = visit.height.max(child_visit.height + 1);
+            if mode == CompletionMode::Aggregator {
+                visit.has_completable |= child_visit.has_completable;
+            }
+        }
+        self.path.remove(block);
+        self.visited.insert(block, visit);
+        visit
+    }
+}
@@ -1,11 +1,22 @@
 use opaquekeys::UsageKey;
+use serde_derive::{Deserialize, Serialize};
 use std::collections::BTreeMap;
 
-#[derive(Clone, Copy, Debug, Eq, Hash, Ord, PartialEq, PartialOrd)]
+#[derive(Clone, Copy, Debug, Deserialize, Eq, Hash, Ord, PartialEq, PartialOrd, Serialize)]
+#[serde(rename_all = "lowercase")]
 pub enum CompletionMode {
     Aggregator,
     Completable,
     Excluded,
+    /// Bonus content.  An optional block is completable, or aggregates its
+    /// children if it has any, and reports its own completion, but it
+    /// contributes nothing to its parents.
+    ///
+    /// An optional block with children gets an `Aggregator` row like any
+    /// aggregator.  An optional block without children is a leaf, and like
+    /// other leaves gets no row: its completion is reported only through
+    /// the completion tree.
+    Optional,
 }
 
 #[derive(Debug)]
@@ -15,8 +26,9 @@ pub struct XBlock {
     pub mode: CompletionMode,
 }
 
-/// Return all available xblock types with their modes as a BTreeMap
-/// This may move to a port/adapter in the future.
+/// Return the built-in xblock types with their modes as a BTreeMap.
+/// `App` gets its modes from an `XBlockModeService`; this is what
+/// `adapters::xblock::BuiltinXBlockModeAdapter` serves.
 pub fn get_xblock_modes() -> BTreeMap<String, CompletionMode> {
     use self::CompletionMode::*;
     let mut map = BTreeMap::new();
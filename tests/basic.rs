@@ -1,9 +1,63 @@
 #![cfg(test)]
 
-use completion::{Aggregator, App, BlockCompletion, User};
-use completion::adapters::{db, stubs};
+use std::cell::Cell;
+use std::collections::BTreeMap;
+use std::rc::Rc;
 
-use opaquekeys::{CourseKey, PartialUsageKey};
+use completion::{
+    Aggregator, App, AppError, BlockCompletion, CompletionStatus, EnrollmentPolicy, User,
+};
+use completion::adapters::{db, stubs, xblock};
+use completion::aggregator::{
+    AggregationConfig, AggregationWarning, Course, ExcludedBlock, ExclusionReason,
+    IncompleteBlock, ModeSource, Remaining, StructureError, Threshold, UnknownBlockTypePolicy,
+};
+use completion::ports;
+use completion::ports::enrollment::{Enrollment, EnrollmentQuery, EnrollmentService};
+use completion::ports::xblock::XBlockModeService;
+use completion::statistics::BlockStatistics;
+use completion::strategy;
+use completion::validation::{validate_structure, validate_structure_with_service};
+use completion::xblock::{get_xblock_modes, CompletionMode};
+
+use opaquekeys::{CourseKey, PartialUsageKey, UsageKey};
+
+type StubApp = App<stubs::StubBlockCompletionAdapter, stubs::StubCourseAdapter, stubs::StubEnrollmentAdapter>;
+
+fn key(raw: &str) -> UsageKey {
+    raw.parse::<PartialUsageKey>().unwrap().try_promote().unwrap()
+}
+
+fn completion(user: &User, block_key: &UsageKey, completion: f64) -> BlockCompletion {
+    BlockCompletion {
+        user: user.clone(),
+        block_key: block_key.clone(),
+        completion,
+    }
+}
+
+/// Key block completions the way the `BlockCompletionService` returns them.
+fn completion_map(completions: Vec<BlockCompletion>) -> BTreeMap<(User, UsageKey), BlockCompletion> {
+    completions
+        .into_iter()
+        .map(|bc| ((bc.user.clone(), bc.block_key.clone()), bc))
+        .collect()
+}
+
+/// An app over the stub adapters, serving a single course with `user`
+/// enrolled in it.  `user` is a known user of the block completion stub.
+fn stub_app(
+    user: &User,
+    coursekey: &CourseKey,
+    structure: BTreeMap<UsageKey, Vec<UsageKey>>,
+    blockcompletions: Vec<BlockCompletion>,
+) -> StubApp {
+    App::new(
+        stubs::StubBlockCompletionAdapter::new(blockcompletions).with_users(vec![user.clone()]),
+        stubs::StubCourseAdapter::new(coursekey.clone(), structure),
+        stubs::StubEnrollmentAdapter::new(vec![(user.clone(), coursekey.clone())]),
+    )
+}
 
 #[test]
 fn test_get_user_completion() {
@@ -78,6 +132,1628 @@ fn test_get_user_completion() {
     )
 }
 
+#[test]
+fn test_get_user_completion_errors() {
+    let user = User {
+        username: "test_user".to_owned(),
+    };
+    let course: CourseKey = "course-v1:edX+DemoX+DemoCourse".parse().unwrap();
+    let missing: CourseKey = "course-v1:edX+DemoX+Missing".parse().unwrap();
+    let root = "block-v1:edX+DemoX+DemoCourse+type@course+block@course"
+        .parse::<PartialUsageKey>()
+        .unwrap()
+        .try_promote()
+        .unwrap();
+
+    let blockcompletion_service = stubs::StubBlockCompletionAdapter::new(vec![]);
+    let course_service = stubs::StubCourseAdapter::new(
+        course.clone(),
+        vec![(root, vec![])].into_iter().collect(),
+    );
+    let enrollment_service = stubs::StubEnrollmentAdapter::new(vec![
+        (user.clone(), course.clone()),
+        (user.clone(), missing.clone()),
+    ]);
+
+    let app = App::new(blockcompletion_service, course_service, enrollment_service);
+    let other = User {
+        username: "other_user".to_owned(),
+    };
+    match app.get_user_completion(&other, &course) {
+        Err(AppError::NotEnrolled) => {}
+        result => panic!("Expected NotEnrolled, got {:?}", result),
+    }
+    match app.get_user_completion(&user, &missing) {
+        Err(AppError::CourseNotFound) => {}
+        result => panic!("Expected CourseNotFound, got {:?}", result),
+    }
+}
+
+struct FailingEnrollmentAdapter;
+
+impl EnrollmentService for FailingEnrollmentAdapter {
+    fn query_enrollment(&self, _query: &EnrollmentQuery) -> ports::Result<Vec<Enrollment>> {
+        Err(ports::ServiceError::Timeout(Box::new(std::io::Error::new(
+            std::io::ErrorKind::TimedOut,
+            "enrollment backend timed out",
+        ))))
+    }
+}
+
+#[test]
+fn test_enrollment_policy() {
+    let user = User {
+        username: "test_user".to_owned(),
+    };
+    let course: CourseKey = "course-v1:edX+DemoX+DemoCourse".parse().unwrap();
+    let root = "block-v1:edX+DemoX+DemoCourse+type@course+block@course"
+        .parse::<PartialUsageKey>()
+        .unwrap()
+        .try_promote()
+        .unwrap();
+    let structure: BTreeMap<_, _> = vec![(root, vec![])].into_iter().collect();
+
+    let app = App::new(
+        stubs::StubBlockCompletionAdapter::new(vec![]),
+        stubs::StubCourseAdapter::new(course.clone(), structure.clone()),
+        FailingEnrollmentAdapter,
+    );
+    assert!(app.get_user_completion(&user, &course).is_ok());
+    // Course-wide calls have no users to fail open with.
+    match app.get_course_completion(&course) {
+        Err(AppError::Enrollment(_)) => {}
+        result => panic!("Expected Enrollment error, got {:?}", result),
+    }
+    match app.get_course_statistics(&course, 4) {
+        Err(AppError::Enrollment(_)) => {}
+        result => panic!("Expected Enrollment error, got {:?}", result),
+    }
+    let app = app.with_enrollment_policy(EnrollmentPolicy::Strict);
+    match app.get_user_completion(&user, &course) {
+        Err(AppError::Enrollment(_)) => {}
+        result => panic!("Expected Enrollment error, got {:?}", result),
+    }
+
+    let duplicated = App::new(
+        stubs::StubBlockCompletionAdapter::new(vec![]),
+        stubs::StubCourseAdapter::new(course.clone(), structure.clone()),
+        stubs::StubEnrollmentAdapter::new(vec![
+            (user.clone(), course.clone()),
+            (user.clone(), course.clone()),
+        ]),
+    ).with_enrollment_policy(EnrollmentPolicy::Strict);
+    match duplicated.get_user_completion(&user, &course) {
+        Err(AppError::Enrollment(ports::ServiceError::MultipleResults)) => {}
+        result => panic!("Expected MultipleResults, got {:?}", result),
+    }
+
+    let skipped = App::new(
+        stubs::StubBlockCompletionAdapter::new(vec![]),
+        stubs::StubCourseAdapter::new(course.clone(), structure),
+        stubs::StubEnrollmentAdapter::new(vec![]),
+    ).with_enrollment_policy(EnrollmentPolicy::Skip);
+    assert!(skipped.get_user_completion(&user, &course).is_ok());
+}
+
+#[test]
+fn test_service_errors() {
+    use std::error::Error;
+
+    let user = User {
+        username: "test_user".to_owned(),
+    };
+    let course: CourseKey = "course-v1:edX+DemoX+DemoCourse".parse().unwrap();
+    let root = "block-v1:edX+DemoX+DemoCourse+type@course+block@course"
+        .parse::<PartialUsageKey>()
+        .unwrap()
+        .try_promote()
+        .unwrap();
+    let structure: BTreeMap<_, _> = vec![(root, vec![])].into_iter().collect();
+
+    let app = App::new(
+        stubs::StubBlockCompletionAdapter::new(vec![]),
+        stubs::StubCourseAdapter::new(course.clone(), structure),
+        FailingEnrollmentAdapter,
+    ).with_enrollment_policy(EnrollmentPolicy::Strict);
+    let err = app.get_user_completion(&user, &course).unwrap_err();
+    assert!(err.is_transient());
+    assert_eq!(err.to_string(), "Enrollment service failed");
+    let service_error = err.source().unwrap();
+    assert_eq!(service_error.to_string(), "Timed out");
+    let io_error = service_error
+        .source()
+        .unwrap()
+        .downcast_ref::<std::io::Error>()
+        .unwrap();
+    assert_eq!(io_error.kind(), std::io::ErrorKind::TimedOut);
+    assert_eq!(io_error.to_string(), "enrollment backend timed out");
+
+    let bad_key = "not a key".parse::<PartialUsageKey>().err().unwrap();
+    let invalid = ports::ServiceError::InvalidData(Box::new(bad_key));
+    assert!(!invalid.is_transient());
+    assert!(invalid.source().is_some());
+    let rate_limited = ports::ServiceError::RateLimited {
+        retry_after: Some(std::time::Duration::from_secs(30)),
+    };
+    assert!(rate_limited.is_transient());
+    assert_eq!(rate_limited.to_string(), "Rate limited, retry after 30s");
+    assert!(rate_limited.source().is_none());
+
+    match app.get_structure_report(&"course-v1:edX+DemoX+Missing".parse().unwrap()) {
+        Err(ref err @ AppError::CourseNotFound) => assert!(!err.is_transient()),
+        other => panic!("Expected CourseNotFound, got {:?}", other),
+    }
+}
+
+#[test]
+fn test_get_course_completion() {
+    let user = User {
+        username: "test_user".to_owned(),
+    };
+    let other = User {
+        username: "other_user".to_owned(),
+    };
+    let course: CourseKey = "course-v1:edX+DemoX+DemoCourse".parse().unwrap();
+    let usagekeys: Vec<_> = vec![
+        "block-v1:edX+DemoX+DemoCourse+type@course+block@course"
+            .parse()
+            .unwrap(),
+        "block-v1:edX+DemoX+DemoCourse+type@html+block@intro"
+            .parse()
+            .unwrap(),
+        "block-v1:edX+DemoX+DemoCourse+type@poll+block@poll"
+            .parse()
+            .unwrap(),
+    ].into_iter()
+        .map(|key: PartialUsageKey| key.try_promote().unwrap())
+        .collect();
+
+    let blockcompletion_service = stubs::StubBlockCompletionAdapter::new(vec![
+        BlockCompletion {
+            user: user.clone(),
+            block_key: usagekeys[1].clone(),
+            completion: 1.0,
+        },
+        BlockCompletion {
+            user: other.clone(),
+            block_key: usagekeys[1].clone(),
+            completion: 1.0,
+        },
+        BlockCompletion {
+            user: other.clone(),
+            block_key: usagekeys[2].clone(),
+            completion: 0.5,
+        },
+    ]);
+    let course_service = stubs::StubCourseAdapter::new(
+        course.clone(),
+        vec![(
+            usagekeys[0].clone(),
+            vec![usagekeys[1].clone(), usagekeys[2].clone()],
+        )].into_iter()
+            .collect(),
+    );
+    let enrollment_service = stubs::StubEnrollmentAdapter::new(vec![
+        (user.clone(), course.clone()),
+        (other.clone(), course.clone()),
+    ]);
+
+    let app = App::new(blockcompletion_service, course_service, enrollment_service);
+    let result = app.get_course_completion(&course).unwrap();
+    assert_eq!(result.len(), 2);
+    assert_eq!(
+        result[&user],
+        vec![Aggregator {
+            user: user.clone(),
+            block_key: usagekeys[0].clone(),
+            earned: 1.0,
+            possible: 2.0,
+        }]
+    );
+    assert_eq!(
+        result[&other],
+        vec![Aggregator {
+            user: other.clone(),
+            block_key: usagekeys[0].clone(),
+            earned: 1.5,
+            possible: 2.0,
+        }]
+    );
+}
+
+#[test]
+fn test_get_course_statistics() {
+    let course: CourseKey = "course-v1:edX+DemoX+DemoCourse".parse().unwrap();
+    let usagekeys: Vec<_> = vec![
+        "block-v1:edX+DemoX+DemoCourse+type@course+block@course"
+            .parse()
+            .unwrap(),
+        "block-v1:edX+DemoX+DemoCourse+type@html+block@intro"
+            .parse()
+            .unwrap(),
+        "block-v1:edX+DemoX+DemoCourse+type@html+block@outro"
+            .parse()
+            .unwrap(),
+    ].into_iter()
+        .map(|key: PartialUsageKey| key.try_promote().unwrap())
+        .collect();
+    let users: Vec<User> = vec!["none", "half", "most", "all"]
+        .into_iter()
+        .map(|username| User {
+            username: username.to_owned(),
+        })
+        .collect();
+
+    let completion = |user: &User, block: usize, completion| BlockCompletion {
+        user: user.clone(),
+        block_key: usagekeys[block].clone(),
+        completion,
+    };
+    let blockcompletion_service = stubs::StubBlockCompletionAdapter::new(vec![
+        completion(&users[1], 1, 1.0),
+        completion(&users[2], 1, 1.0),
+        completion(&users[2], 2, 0.5),
+        completion(&users[3], 1, 1.0),
+        completion(&users[3], 2, 1.0),
+    ]);
+    let course_service = stubs::StubCourseAdapter::new(
+        course.clone(),
+        vec![(usagekeys[0].clone(), usagekeys[1..].to_vec())]
+            .into_iter()
+            .collect(),
+    );
+    let enrollment_service = stubs::StubEnrollmentAdapter::new(
+        users.iter().map(|user| (user.clone(), course.clone())).collect(),
+    );
+
+    let app = App::new(blockcompletion_service, course_service, enrollment_service);
+    let statistics = app.get_course_statistics(&course, 4).unwrap();
+    assert_eq!(statistics.users, 4);
+    assert_eq!(
+        statistics.blocks,
+        vec![BlockStatistics {
+            block_key: usagekeys[0].clone(),
+            mean_percent: Some(0.5625),
+            median_percent: Some(0.625),
+            not_applicable: 0,
+            not_started: 1,
+            in_progress: 2,
+            complete: 1,
+            histogram: vec![1, 0, 1, 2],
+        }]
+    );
+}
+
+#[test]
+fn test_get_users_completion() {
+    let user = User {
+        username: "test_user".to_owned(),
+    };
+    let unenrolled = User {
+        username: "unenrolled_user".to_owned(),
+    };
+    let course: CourseKey = "course-v1:edX+DemoX+DemoCourse".parse().unwrap();
+    let usagekeys: Vec<_> = vec![
+        "block-v1:edX+DemoX+DemoCourse+type@course+block@course"
+            .parse()
+            .unwrap(),
+        "block-v1:edX+DemoX+DemoCourse+type@html+block@intro"
+            .parse()
+            .unwrap(),
+    ].into_iter()
+        .map(|key: PartialUsageKey| key.try_promote().unwrap())
+        .collect();
+
+    let blockcompletion_service = stubs::StubBlockCompletionAdapter::new(vec![
+        BlockCompletion {
+            user: user.clone(),
+            block_key: usagekeys[1].clone(),
+            completion: 1.0,
+        },
+        BlockCompletion {
+            user: unenrolled.clone(),
+            block_key: usagekeys[1].clone(),
+            completion: 1.0,
+        },
+    ]);
+    let course_service = stubs::StubCourseAdapter::new(
+        course.clone(),
+        vec![(usagekeys[0].clone(), vec![usagekeys[1].clone()])]
+            .into_iter()
+            .collect(),
+    );
+    let enrollment_service =
+        stubs::StubEnrollmentAdapter::new(vec![(user.clone(), course.clone())]);
+
+    let app = App::new(blockcompletion_service, course_service, enrollment_service);
+    let result = app
+        .get_users_completion(&[user.clone(), unenrolled.clone()], &course)
+        .unwrap();
+    assert_eq!(result.keys().collect::<Vec<_>>(), vec![&user]);
+    assert_eq!(
+        result[&user],
+        vec![Aggregator {
+            user: user.clone(),
+            block_key: usagekeys[0].clone(),
+            earned: 1.0,
+            possible: 1.0,
+        }]
+    );
+    assert!(app.get_users_completion(&[], &course).unwrap().is_empty());
+}
+
+#[test]
+fn test_get_enrolled_courses_completion() {
+    let user = User {
+        username: "test_user".to_owned(),
+    };
+    let course: CourseKey = "course-v1:edX+DemoX+DemoCourse".parse().unwrap();
+    let missing: CourseKey = "course-v1:edX+DemoX+Missing".parse().unwrap();
+    let usagekeys: Vec<_> = vec![
+        "block-v1:edX+DemoX+DemoCourse+type@course+block@course"
+            .parse()
+            .unwrap(),
+        "block-v1:edX+DemoX+DemoCourse+type@html+block@intro"
+            .parse()
+            .unwrap(),
+        "block-v1:edX+DemoX+DemoCourse+type@html+block@outro"
+            .parse()
+            .unwrap(),
+    ].into_iter()
+        .map(|key: PartialUsageKey| key.try_promote().unwrap())
+        .collect();
+
+    let blockcompletion_service = stubs::StubBlockCompletionAdapter::new(vec![
+        BlockCompletion {
+            user: user.clone(),
+            block_key: usagekeys[1].clone(),
+            completion: 1.0,
+        },
+    ]);
+    let course_service = stubs::StubCourseAdapter::new(
+        course.clone(),
+        vec![(
+            usagekeys[0].clone(),
+            vec![usagekeys[1].clone(), usagekeys[2].clone()],
+        )].into_iter()
+            .collect(),
+    );
+    let enrollment_service = stubs::StubEnrollmentAdapter::new(vec![
+        (user.clone(), course.clone()),
+        (user.clone(), missing.clone()),
+    ]);
+
+    let app = App::new(blockcompletion_service, course_service, enrollment_service);
+    let result = app.get_enrolled_courses_completion(&user).unwrap();
+    assert_eq!(result.len(), 2);
+    assert_eq!(
+        result[&course].as_ref().unwrap(),
+        &Aggregator {
+            user: user.clone(),
+            block_key: usagekeys[0].clone(),
+            earned: 1.0,
+            possible: 2.0,
+        }
+    );
+    match result[&missing] {
+        Err(AppError::CourseNotFound) => {}
+        ref summary => panic!("Expected CourseNotFound, got {:?}", summary),
+    }
+}
+
+#[test]
+fn test_get_subtree_completion() {
+    let user = User {
+        username: "test_user".to_owned(),
+    };
+    let course: CourseKey = "course-v1:edX+DemoX+DemoCourse".parse().unwrap();
+    let usagekeys: Vec<_> = vec![
+        "block-v1:edX+DemoX+DemoCourse+type@course+block@course"
+            .parse()
+            .unwrap(),
+        "block-v1:edX+DemoX+DemoCourse+type@chapter+block@chapter1"
+            .parse()
+            .unwrap(),
+        "block-v1:edX+DemoX+DemoCourse+type@chapter+block@chapter2"
+            .parse()
+            .unwrap(),
+        "block-v1:edX+DemoX+DemoCourse+type@html+block@intro"
+            .parse()
+            .unwrap(),
+        "block-v1:edX+DemoX+DemoCourse+type@html+block@outro"
+            .parse()
+            .unwrap(),
+        "block-v1:edX+DemoX+DemoCourse+type@chapter+block@unknown"
+            .parse()
+            .unwrap(),
+    ].into_iter()
+        .map(|key: PartialUsageKey| key.try_promote().unwrap())
+        .collect();
+
+    let blockcompletion_service = stubs::StubBlockCompletionAdapter::new(vec![
+        BlockCompletion {
+            user: user.clone(),
+            block_key: usagekeys[3].clone(),
+            completion: 1.0,
+        },
+    ]);
+    let course_service = stubs::StubCourseAdapter::new(
+        course.clone(),
+        vec![
+            (
+                usagekeys[0].clone(),
+                vec![usagekeys[1].clone(), usagekeys[2].clone()],
+            ),
+            (usagekeys[1].clone(), vec![usagekeys[3].clone()]),
+            (usagekeys[2].clone(), vec![usagekeys[4].clone()]),
+        ].into_iter()
+            .collect(),
+    );
+    let enrollment_service =
+        stubs::StubEnrollmentAdapter::new(vec![(user.clone(), course.clone())]);
+
+    let app = App::new(blockcompletion_service, course_service, enrollment_service);
+    let result = app.get_subtree_completion(&user, &usagekeys[1]).unwrap();
+    assert_eq!(
+        result,
+        vec![Aggregator {
+            user: user.clone(),
+            block_key: usagekeys[1].clone(),
+            earned: 1.0,
+            possible: 1.0,
+        }]
+    );
+    match app.get_subtree_completion(&user, &usagekeys[5]) {
+        Err(AppError::Structure(StructureError::RootNotFound(ref key))) if key == &usagekeys[5] => {}
+        result => panic!("Expected RootNotFound, got {:?}", result),
+    }
+}
+
+#[test]
+fn test_diamond_structure() {
+    // The course has two chapters that share a vertical.  The shared vertical
+    // and its html block are counted once in the course aggregator.
+    let user = User {
+        username: "test_user".to_owned(),
+    };
+    let course: CourseKey = "course-v1:edX+DemoX+DemoCourse".parse().unwrap();
+    let usagekeys: Vec<_> = vec![
+        "block-v1:edX+DemoX+DemoCourse+type@course+block@course"
+            .parse()
+            .unwrap(),
+        "block-v1:edX+DemoX+DemoCourse+type@chapter+block@chapter1"
+            .parse()
+            .unwrap(),
+        "block-v1:edX+DemoX+DemoCourse+type@chapter+block@chapter2"
+            .parse()
+            .unwrap(),
+        "block-v1:edX+DemoX+DemoCourse+type@vertical+block@shared"
+            .parse()
+            .unwrap(),
+        "block-v1:edX+DemoX+DemoCourse+type@html+block@shared_html"
+            .parse()
+            .unwrap(),
+        "block-v1:edX+DemoX+DemoCourse+type@video+block@video"
+            .parse()
+            .unwrap(),
+    ].into_iter()
+        .map(|key: PartialUsageKey| key.try_promote().unwrap())
+        .collect();
+
+    let blockcompletion_service = stubs::StubBlockCompletionAdapter::new(vec![
+        BlockCompletion {
+            user: user.clone(),
+            block_key: usagekeys[4].clone(),
+            completion: 1.0,
+        },
+    ]);
+    let course_service = stubs::StubCourseAdapter::new(
+        course.clone(),
+        vec![
+            (
+                usagekeys[0].clone(),
+                vec![usagekeys[1].clone(), usagekeys[2].clone()],
+            ),
+            (usagekeys[1].clone(), vec![usagekeys[3].clone()]),
+            (
+                usagekeys[2].clone(),
+                vec![usagekeys[3].clone(), usagekeys[5].clone()],
+            ),
+            (usagekeys[3].clone(), vec![usagekeys[4].clone()]),
+        ].into_iter()
+            .collect(),
+    );
+    let enrollment_service =
+        stubs::StubEnrollmentAdapter::new(vec![(user.clone(), course.clone())]);
+
+    let app = App::new(blockcompletion_service, course_service, enrollment_service);
+    let result = app.get_user_completion(&user, &course).unwrap();
+    assert_eq!(
+        result,
+        vec![
+            Aggregator {
+                user: user.clone(),
+                block_key: usagekeys[3].clone(),
+                earned: 1.0,
+                possible: 1.0,
+            },
+            Aggregator {
+                user: user.clone(),
+                block_key: usagekeys[1].clone(),
+                earned: 1.0,
+                possible: 1.0,
+            },
+            Aggregator {
+                user: user.clone(),
+                block_key: usagekeys[2].clone(),
+                earned: 1.0,
+                possible: 2.0,
+            },
+            Aggregator {
+                user: user.clone(),
+                block_key: usagekeys[0].clone(),
+                earned: 1.0,
+                possible: 2.0,
+            },
+        ]
+    );
+}
+
+#[test]
+fn test_weighted_completion() {
+    let user = User {
+        username: "test_user".to_owned(),
+    };
+    let course: CourseKey = "course-v1:edX+DemoX+DemoCourse".parse().unwrap();
+    let usagekeys: Vec<_> = vec![
+        "block-v1:edX+DemoX+DemoCourse+type@course+block@course"
+            .parse()
+            .unwrap(),
+        "block-v1:edX+DemoX+DemoCourse+type@video+block@lecture"
+            .parse()
+            .unwrap(),
+        "block-v1:edX+DemoX+DemoCourse+type@poll+block@poll"
+            .parse()
+            .unwrap(),
+        "block-v1:edX+DemoX+DemoCourse+type@html+block@intro"
+            .parse()
+            .unwrap(),
+    ].into_iter()
+        .map(|key: PartialUsageKey| key.try_promote().unwrap())
+        .collect();
+
+    let blockcompletion_service = stubs::StubBlockCompletionAdapter::new(vec![
+        BlockCompletion {
+            user: user.clone(),
+            block_key: usagekeys[1].clone(),
+            completion: 0.5,
+        },
+        BlockCompletion {
+            user: user.clone(),
+            block_key: usagekeys[2].clone(),
+            completion: 1.0,
+        },
+    ]);
+    let course_service = stubs::StubCourseAdapter::new(
+        course.clone(),
+        vec![(usagekeys[0].clone(), usagekeys[1..].to_vec())]
+            .into_iter()
+            .collect(),
+    );
+    let enrollment_service =
+        stubs::StubEnrollmentAdapter::new(vec![(user.clone(), course.clone())]);
+
+    let config = AggregationConfig::default()
+        .with_blocktype_weight("video", 4.0)
+        .with_blocktype_weight("poll", 2.0)
+        .with_block_weights(vec![(usagekeys[2].clone(), 0.5)].into_iter().collect());
+    let app = App::new(blockcompletion_service, course_service, enrollment_service)
+        .with_aggregation_config(config);
+    let result = app.get_user_completion(&user, &course).unwrap();
+    assert_eq!(
+        result,
+        vec![Aggregator {
+            user: user.clone(),
+            block_key: usagekeys[0].clone(),
+            earned: 2.5,
+            possible: 5.5,
+        }]
+    );
+}
+
+#[test]
+#[should_panic(expected = "Weight must be finite and not negative")]
+fn test_negative_weight() {
+    AggregationConfig::default().with_blocktype_weight("video", -1.0);
+}
+
+#[test]
+#[should_panic(expected = "Weight must be finite and not negative")]
+fn test_non_finite_weight() {
+    let block = key("block-v1:edX+DemoX+DemoCourse+type@video+block@video");
+    AggregationConfig::default().with_block_weights(vec![(block, f64::NAN)].into_iter().collect());
+}
+
+#[test]
+#[should_panic(expected = "Threshold minimum must be greater than 0 and at most 1")]
+fn test_zero_threshold() {
+    AggregationConfig::default().with_threshold(Threshold::partial(0.0));
+}
+
+#[test]
+#[should_panic(expected = "Threshold minimum must be greater than 0 and at most 1")]
+fn test_non_finite_threshold() {
+    let threshold = Threshold {
+        minimum: f64::NAN,
+        binary: true,
+    };
+    AggregationConfig::default().with_blocktype_threshold("poll", threshold);
+}
+
+#[test]
+fn test_aggregation_strategies() {
+    let user = User {
+        username: "test_user".to_owned(),
+    };
+    let course = key("block-v1:edX+DemoX+DemoCourse+type@course+block@course");
+    let chapter = key("block-v1:edX+DemoX+DemoCourse+type@chapter+block@chapter");
+    let vertical1 = key("block-v1:edX+DemoX+DemoCourse+type@vertical+block@vertical1");
+    let vertical2 = key("block-v1:edX+DemoX+DemoCourse+type@vertical+block@vertical2");
+    let html1 = key("block-v1:edX+DemoX+DemoCourse+type@html+block@html1");
+    let html2 = key("block-v1:edX+DemoX+DemoCourse+type@html+block@html2");
+    let html3 = key("block-v1:edX+DemoX+DemoCourse+type@html+block@html3");
+
+    let structure = vec![
+        (course.clone(), vec![chapter.clone()]),
+        (chapter.clone(), vec![vertical1.clone(), vertical2.clone()]),
+        (vertical1.clone(), vec![html1.clone(), html2.clone()]),
+        (vertical2.clone(), vec![html3.clone()]),
+    ].into_iter()
+        .collect();
+    let completions = completion_map(vec![
+        completion(&user, &html1, 1.0),
+        completion(&user, &html2, 0.5),
+        completion(&user, &html3, 1.0),
+    ]);
+    let scores = |config: AggregationConfig| {
+        Course::from_structure(&structure, &config)
+            .unwrap()
+            .aggregate(&user, &completions)
+            .into_iter()
+            .map(|agg| (agg.earned, agg.possible))
+            .collect::<Vec<_>>()
+    };
+
+    // Post-order: vertical1, vertical2, chapter, course
+    assert_eq!(
+        scores(AggregationConfig::default()),
+        vec![(1.5, 2.0), (1.0, 1.0), (2.5, 3.0), (2.5, 3.0)]
+    );
+    let config = AggregationConfig::default()
+        .with_blocktype_strategy("vertical", strategy::AllOrNothing)
+        .with_blocktype_strategy("chapter", strategy::Minimum);
+    assert_eq!(
+        scores(config.clone()),
+        vec![(0.0, 2.0), (1.0, 1.0), (0.0, 3.0), (0.0, 3.0)]
+    );
+    let config = config.with_block_strategy(vertical1.clone(), strategy::Sum);
+    assert_eq!(
+        scores(config),
+        vec![(1.5, 2.0), (1.0, 1.0), (2.25, 3.0), (2.25, 3.0)]
+    );
+    let config = AggregationConfig::default().with_blocktype_strategy("chapter", strategy::MeanPercent);
+    assert_eq!(
+        scores(config),
+        vec![(1.5, 2.0), (1.0, 1.0), (2.625, 3.0), (2.625, 3.0)]
+    );
+}
+
+#[test]
+fn test_completion_thresholds() {
+    let user = User {
+        username: "test_user".to_owned(),
+    };
+    let course = key("block-v1:edX+DemoX+DemoCourse+type@course+block@course");
+    let watched = key("block-v1:edX+DemoX+DemoCourse+type@video+block@watched");
+    let skimmed = key("block-v1:edX+DemoX+DemoCourse+type@video+block@skimmed");
+    let read = key("block-v1:edX+DemoX+DemoCourse+type@html+block@read");
+    let glanced = key("block-v1:edX+DemoX+DemoCourse+type@html+block@glanced");
+
+    let structure = vec![(
+        course.clone(),
+        vec![watched.clone(), skimmed.clone(), read.clone(), glanced.clone()],
+    )].into_iter()
+        .collect();
+    let completions = completion_map(vec![
+        completion(&user, &watched, 0.95),
+        completion(&user, &skimmed, 0.8),
+        completion(&user, &read, 0.6),
+        completion(&user, &glanced, 0.4),
+    ]);
+    let config = AggregationConfig::default()
+        .with_threshold(Threshold::partial(0.5))
+        .with_blocktype_threshold("video", Threshold::binary(0.9));
+    let result = Course::from_structure(&structure, &config)
+        .unwrap()
+        .aggregate_root(&user, &completions);
+    assert_eq!(result.earned, 1.6);
+    assert_eq!(result.possible, 4.0);
+}
+
+#[test]
+fn test_reaggregate() {
+    let user = User {
+        username: "test_user".to_owned(),
+    };
+    let course = key("block-v1:edX+DemoX+DemoCourse+type@course+block@course");
+    let chapter1 = key("block-v1:edX+DemoX+DemoCourse+type@chapter+block@chapter1");
+    let chapter2 = key("block-v1:edX+DemoX+DemoCourse+type@chapter+block@chapter2");
+    let shared = key("block-v1:edX+DemoX+DemoCourse+type@vertical+block@shared");
+    let html = key("block-v1:edX+DemoX+DemoCourse+type@html+block@html");
+    let poll = key("block-v1:edX+DemoX+DemoCourse+type@poll+block@poll");
+    let video = key("block-v1:edX+DemoX+DemoCourse+type@video+block@video");
+
+    // The shared vertical averages its blocks, and is counted once in the
+    // course even though both chapters contain it.
+    let structure = vec![
+        (course.clone(), vec![chapter1.clone(), chapter2.clone()]),
+        (chapter1.clone(), vec![shared.clone()]),
+        (chapter2.clone(), vec![shared.clone(), video.clone()]),
+        (shared.clone(), vec![html.clone(), poll.clone()]),
+    ].into_iter()
+        .collect();
+    let config = AggregationConfig::default().with_blocktype_strategy("vertical", strategy::MeanPercent);
+    let course = Course::from_structure(&structure, &config).unwrap();
+    let mut completions = completion_map(vec![completion(&user, &video, 0.5)]);
+    let previous = course.aggregate(&user, &completions);
+    let root = previous.iter().find(|agg| &agg.block_key == course.root()).unwrap();
+    assert_eq!((root.earned, root.possible), (0.5, 3.0));
+
+    let changed = completion(&user, &html, 1.0);
+    let updated = course.reaggregate(&previous, &changed, &completions);
+    completions.insert((user.clone(), html.clone()), changed);
+    let expected = course.aggregate(&user, &completions);
+    assert_eq!(updated.len(), 4);
+    assert_eq!(updated, expected);
+
+    let changed = completion(&user, &video, 1.0);
+    let updated = course.reaggregate(&expected, &changed, &completions);
+    completions.insert((user.clone(), video.clone()), changed);
+    let expected: Vec<_> = course
+        .aggregate(&user, &completions)
+        .into_iter()
+        .filter(|agg| agg.block_key != shared && agg.block_key != chapter1)
+        .collect();
+    assert_eq!(updated, expected);
+}
+
+#[test]
+fn test_shared_non_additive_blocks() {
+    let user = User {
+        username: "test_user".to_owned(),
+    };
+    let course = key("block-v1:edX+DemoX+DemoCourse+type@course+block@course");
+    let chapter = key("block-v1:edX+DemoX+DemoCourse+type@chapter+block@chapter");
+    let sequential = key("block-v1:edX+DemoX+DemoCourse+type@sequential+block@sequential");
+    let vertical1 = key("block-v1:edX+DemoX+DemoCourse+type@vertical+block@vertical1");
+    let vertical2 = key("block-v1:edX+DemoX+DemoCourse+type@vertical+block@vertical2");
+    let shared = key("block-v1:edX+DemoX+DemoCourse+type@html+block@shared");
+    let video = key("block-v1:edX+DemoX+DemoCourse+type@video+block@video");
+    let sequential1 = key("block-v1:edX+DemoX+DemoCourse+type@sequential+block@sequential1");
+    let sequential2 = key("block-v1:edX+DemoX+DemoCourse+type@sequential+block@sequential2");
+
+    // Two all-or-nothing verticals share an html block under a summing
+    // sequential, which would count the html block twice.
+    let structure = vec![
+        (course.clone(), vec![sequential.clone()]),
+        (sequential.clone(), vec![vertical1.clone(), vertical2.clone()]),
+        (vertical1.clone(), vec![shared.clone()]),
+        (vertical2.clone(), vec![shared.clone(), video.clone()]),
+    ].into_iter()
+        .collect();
+    let config = AggregationConfig::default().with_blocktype_strategy("vertical", strategy::AllOrNothing);
+    match Course::from_structure(&structure, &config) {
+        Err(StructureError::SharedNonAdditiveBlock {
+            ref aggregator,
+            ref block,
+        }) if aggregator == &sequential && block == &shared => {}
+        Err(err) => panic!("Expected SharedNonAdditiveBlock, got {:?}", err),
+        Ok(_) => panic!("Expected SharedNonAdditiveBlock"),
+    }
+
+    // Sharing a whole all-or-nothing vertical is fine: it is one unit, and
+    // counted once.
+    let structure = vec![
+        (course.clone(), vec![chapter.clone(), sequential.clone()]),
+        (chapter.clone(), vec![vertical1.clone(), shared.clone()]),
+        (sequential.clone(), vec![vertical1.clone()]),
+        (vertical1.clone(), vec![video.clone()]),
+    ].into_iter()
+        .collect();
+    let result = Course::from_structure(&structure, &config)
+        .unwrap()
+        .aggregate_root(&user, &completion_map(vec![completion(&user, &video, 1.0)]));
+    assert_eq!((result.earned, result.possible), (1.0, 2.0));
+
+    // Two sequentials share a vertical under a non-additive chapter, which
+    // would count the vertical once for each sequential.
+    let structure: BTreeMap<_, _> = vec![
+        (course.clone(), vec![chapter.clone()]),
+        (chapter.clone(), vec![sequential1.clone(), sequential2.clone()]),
+        (sequential1.clone(), vec![vertical1.clone()]),
+        (sequential2.clone(), vec![vertical1.clone()]),
+        (vertical1.clone(), vec![shared.clone()]),
+    ].into_iter()
+        .collect();
+    let configs = vec![
+        AggregationConfig::default().with_blocktype_strategy("chapter", strategy::MeanPercent),
+        AggregationConfig::default().with_blocktype_strategy("chapter", strategy::AllOrNothing),
+        AggregationConfig::default().with_blocktype_strategy("chapter", strategy::Minimum),
+    ];
+    for config in configs {
+        match Course::from_structure(&structure, &config) {
+            Err(StructureError::SharedNonAdditiveBlock {
+                ref aggregator,
+                ref block,
+            }) if aggregator == &chapter && block == &shared => {}
+            Err(err) => panic!("Expected SharedNonAdditiveBlock, got {:?}", err),
+            Ok(_) => panic!("Expected SharedNonAdditiveBlock"),
+        }
+    }
+
+    // Under a summing chapter the vertical is a single unit.
+    let result = Course::from_structure(&structure, &AggregationConfig::default())
+        .unwrap()
+        .aggregate_root(&user, &completion_map(vec![completion(&user, &shared, 1.0)]));
+    assert_eq!((result.earned, result.possible), (1.0, 1.0));
+}
+
+#[test]
+fn test_completion_vectors() {
+    let users: Vec<User> = vec!["ann", "bob", "cat", "dan"]
+        .into_iter()
+        .map(|username| User {
+            username: username.to_owned(),
+        })
+        .collect();
+    let course = key("block-v1:edX+DemoX+DemoCourse+type@course+block@course");
+    let chapter1 = key("block-v1:edX+DemoX+DemoCourse+type@chapter+block@chapter1");
+    let chapter2 = key("block-v1:edX+DemoX+DemoCourse+type@chapter+block@chapter2");
+    let shared = key("block-v1:edX+DemoX+DemoCourse+type@vertical+block@shared");
+    let html = key("block-v1:edX+DemoX+DemoCourse+type@html+block@html");
+    let video = key("block-v1:edX+DemoX+DemoCourse+type@video+block@video");
+    let poll = key("block-v1:edX+DemoX+DemoCourse+type@poll+block@poll");
+    let unlisted = key("block-v1:edX+DemoX+DemoCourse+type@html+block@unlisted");
+
+    let structure = vec![
+        (course.clone(), vec![chapter1.clone(), chapter2.clone()]),
+        (chapter1.clone(), vec![shared.clone(), poll.clone()]),
+        (chapter2.clone(), vec![shared.clone(), video.clone()]),
+        (shared.clone(), vec![html.clone()]),
+    ].into_iter()
+        .collect();
+    let config = AggregationConfig::default()
+        .with_blocktype_weight("video", 2.0)
+        .with_blocktype_threshold("poll", Threshold::binary(0.5));
+    let course = Course::from_structure(&structure, &config).unwrap();
+    // Dan has no completions, and the unlisted block isn't in the course.
+    let completions = completion_map(vec![
+        completion(&users[0], &html, 1.0),
+        completion(&users[0], &video, 0.25),
+        completion(&users[1], &poll, 0.6),
+        completion(&users[1], &unlisted, 1.0),
+        completion(&users[2], &html, 0.5),
+        completion(&users[2], &video, 1.0),
+        completion(&users[2], &poll, 0.4),
+    ]);
+
+    let vectors = course.completion_vectors(completions.values());
+    assert_eq!(vectors.keys().cloned().collect::<Vec<_>>(), users[..3].iter().collect::<Vec<_>>());
+    for user in &users {
+        let vector = vectors.get(user).map(Vec::as_slice).unwrap_or(&[]);
+        assert_eq!(
+            course.aggregate_completions(user, vector),
+            course.aggregate(user, &completions),
+            "{}",
+            user.username
+        );
+    }
+}
+
+#[test]
+fn test_aggregate_tree() {
+    let user = User {
+        username: "test_user".to_owned(),
+    };
+    let course = key("block-v1:edX+DemoX+DemoCourse+type@course+block@course");
+    let chapter = key("block-v1:edX+DemoX+DemoCourse+type@chapter+block@chapter");
+    let html = key("block-v1:edX+DemoX+DemoCourse+type@html+block@html");
+    let discussion = key("block-v1:edX+DemoX+DemoCourse+type@discussion+block@discussion");
+
+    let structure = vec![
+        (course.clone(), vec![chapter.clone()]),
+        (chapter.clone(), vec![html.clone(), discussion.clone()]),
+    ].into_iter()
+        .collect();
+    let completions = completion_map(vec![completion(&user, &html, 0.5)]);
+    let course = Course::from_structure(&structure, &AggregationConfig::default()).unwrap();
+
+    let tree = course.aggregate_tree(&user, &completions, false);
+    assert_eq!(tree.children.len(), 1);
+    assert!(tree.children[0].children.is_empty());
+
+    let tree = course.aggregate_tree(&user, &completions, true);
+    assert_eq!(
+        serde_json::to_value(&tree.children[0]).unwrap(),
+        serde_json::json!({
+            "block_key": "block-v1:edX+DemoX+DemoCourse+type@chapter+block@chapter",
+            "blocktype": "chapter",
+            "mode": "aggregator",
+            "earned": 0.5,
+            "possible": 1.0,
+            "percent": 0.5,
+            "status": "in_progress",
+            "children": [
+                {
+                    "block_key": "block-v1:edX+DemoX+DemoCourse+type@html+block@html",
+                    "blocktype": "html",
+                    "mode": "completable",
+                    "earned": 0.5,
+                    "possible": 1.0,
+                    "percent": 0.5,
+                    "status": "in_progress",
+                    "completion": 0.5,
+                    "children": [],
+                },
+                {
+                    "block_key": "block-v1:edX+DemoX+DemoCourse+type@discussion+block@discussion",
+                    "blocktype": "discussion",
+                    "mode": "excluded",
+                    "earned": 0.0,
+                    "possible": 0.0,
+                    "percent": null,
+                    "status": "not_applicable",
+                    "children": [],
+                },
+            ],
+        })
+    );
+}
+
+#[test]
+fn test_not_applicable_blocks() {
+    let user = User {
+        username: "test_user".to_owned(),
+    };
+    let root = key("block-v1:edX+DemoX+DemoCourse+type@course+block@course");
+    let lessons = key("block-v1:edX+DemoX+DemoCourse+type@chapter+block@lessons");
+    let forum = key("block-v1:edX+DemoX+DemoCourse+type@chapter+block@forum");
+    let html = key("block-v1:edX+DemoX+DemoCourse+type@html+block@html");
+    let discussion = key("block-v1:edX+DemoX+DemoCourse+type@discussion+block@discussion");
+
+    let structure = vec![
+        (root.clone(), vec![lessons.clone(), forum.clone()]),
+        (lessons.clone(), vec![html.clone()]),
+        (forum.clone(), vec![discussion.clone()]),
+    ].into_iter()
+        .collect();
+    let completions = BTreeMap::new();
+
+    let course = Course::from_structure(&structure, &AggregationConfig::default()).unwrap();
+    let result = course.aggregate(&user, &completions);
+    let statuses: Vec<_> = result
+        .iter()
+        .map(|agg| (agg.block_key.clone(), agg.percent(), agg.status()))
+        .collect();
+    assert_eq!(
+        statuses,
+        vec![
+            (lessons.clone(), Some(0.0), CompletionStatus::NotStarted),
+            (forum.clone(), None, CompletionStatus::NotApplicable),
+            (root.clone(), Some(0.0), CompletionStatus::NotStarted),
+        ]
+    );
+    assert_eq!(
+        serde_json::to_value(&result[1]).unwrap(),
+        serde_json::json!({
+            "user": {"username": "test_user"},
+            "block_key": "block-v1:edX+DemoX+DemoCourse+type@chapter+block@forum",
+            "earned": 0.0,
+            "possible": 0.0,
+            "percent": null,
+            "status": "not_applicable",
+        })
+    );
+
+    let config = AggregationConfig::default().with_omit_not_applicable(true);
+    let course = Course::from_structure(&structure, &config).unwrap();
+    let blocks: Vec<_> = course
+        .aggregate(&user, &completions)
+        .into_iter()
+        .map(|agg| agg.block_key)
+        .collect();
+    assert_eq!(blocks, vec![lessons.clone(), root]);
+    let tree = course.aggregate_tree(&user, &completions, false);
+    assert_eq!(tree.children.len(), 1);
+    assert_eq!(tree.children[0].block_key, lessons);
+}
+
+#[test]
+fn test_get_remaining_blocks() {
+    let user = User {
+        username: "test_user".to_owned(),
+    };
+    let coursekey: CourseKey = "course-v1:edX+DemoX+DemoCourse".parse().unwrap();
+    let course = key("block-v1:edX+DemoX+DemoCourse+type@course+block@course");
+    let chapter = key("block-v1:edX+DemoX+DemoCourse+type@chapter+block@chapter");
+    let done = key("block-v1:edX+DemoX+DemoCourse+type@html+block@done");
+    let started = key("block-v1:edX+DemoX+DemoCourse+type@video+block@started");
+    let untouched = key("block-v1:edX+DemoX+DemoCourse+type@poll+block@untouched");
+    let discussion = key("block-v1:edX+DemoX+DemoCourse+type@discussion+block@discussion");
+
+    let app = stub_app(
+        &user,
+        &coursekey,
+        vec![
+            (course.clone(), vec![chapter.clone()]),
+            (
+                chapter.clone(),
+                vec![done.clone(), started.clone(), discussion.clone(), untouched.clone()],
+            ),
+        ].into_iter()
+            .collect(),
+        vec![completion(&user, &done, 1.0), completion(&user, &started, 0.3)],
+    );
+    let remaining = app.get_remaining_blocks(&user, &chapter).unwrap();
+    assert_eq!(
+        remaining,
+        Remaining {
+            block_key: chapter.clone(),
+            incomplete: vec![
+                IncompleteBlock {
+                    block_key: started.clone(),
+                    blocktype: "video".to_owned(),
+                    completion: 0.3,
+                },
+                IncompleteBlock {
+                    block_key: untouched.clone(),
+                    blocktype: "poll".to_owned(),
+                    completion: 0.0,
+                },
+            ],
+            excluded: vec![ExcludedBlock {
+                block_key: discussion.clone(),
+                blocktype: "discussion".to_owned(),
+                reason: ExclusionReason::BlockType,
+            }],
+        }
+    );
+}
+
+#[test]
+fn test_completion_mode_overrides() {
+    let user = User {
+        username: "test_user".to_owned(),
+    };
+    let coursekey: CourseKey = "course-v1:edX+DemoX+DemoCourse".parse().unwrap();
+    let other: CourseKey = "course-v1:edX+DemoX+Other".parse().unwrap();
+    let course = key("block-v1:edX+DemoX+DemoCourse+type@course+block@course");
+    let banner = key("block-v1:edX+DemoX+DemoCourse+type@html+block@banner");
+    let lesson = key("block-v1:edX+DemoX+DemoCourse+type@html+block@lesson");
+    let discussion = key("block-v1:edX+DemoX+DemoCourse+type@discussion+block@discussion");
+    let poll = key("block-v1:edX+DemoX+DemoCourse+type@poll+block@poll");
+
+    let structure: BTreeMap<_, _> = vec![(
+        course.clone(),
+        vec![banner.clone(), lesson.clone(), discussion.clone(), poll.clone()],
+    )].into_iter()
+        .collect();
+    let config = AggregationConfig::default()
+        .with_block_mode(banner.clone(), CompletionMode::Excluded)
+        .with_course_mode(coursekey.clone(), "discussion", CompletionMode::Completable)
+        .with_course_mode(other, "poll", CompletionMode::Excluded);
+
+    let modes = Course::from_structure(&structure, &config)
+        .unwrap()
+        .effective_modes();
+    assert_eq!(
+        modes
+            .iter()
+            .map(|effective| (effective.block_key.clone(), effective.mode, effective.source))
+            .collect::<Vec<_>>(),
+        vec![
+            (banner.clone(), CompletionMode::Excluded, ModeSource::Block),
+            (lesson.clone(), CompletionMode::Completable, ModeSource::BlockType),
+            (discussion.clone(), CompletionMode::Completable, ModeSource::Course),
+            (poll.clone(), CompletionMode::Completable, ModeSource::BlockType),
+            (course.clone(), CompletionMode::Aggregator, ModeSource::BlockType),
+        ]
+    );
+
+    let app = stub_app(&user, &coursekey, structure, vec![completion(&user, &discussion, 1.0)])
+        .with_aggregation_config(config);
+
+    assert_eq!(app.get_effective_modes(&coursekey).unwrap(), modes);
+    let result = app.get_user_completion(&user, &coursekey).unwrap();
+    assert_eq!((result[0].earned, result[0].possible), (1.0, 3.0));
+    let remaining = app.get_remaining_blocks(&user, &course).unwrap();
+    assert_eq!(
+        remaining.excluded,
+        vec![ExcludedBlock {
+            block_key: banner.clone(),
+            blocktype: "html".to_owned(),
+            reason: ExclusionReason::BlockOverride,
+        }]
+    );
+}
+
+#[test]
+fn test_unknown_blocktype_policy() {
+    let user = User {
+        username: "test_user".to_owned(),
+    };
+    let coursekey: CourseKey = "course-v1:edX+DemoX+DemoCourse".parse().unwrap();
+    let course = key("block-v1:edX+DemoX+DemoCourse+type@course+block@course");
+    let html = key("block-v1:edX+DemoX+DemoCourse+type@html+block@html");
+    let lti = key("block-v1:edX+DemoX+DemoCourse+type@lti_consumer+block@lti");
+    let dnd = key("block-v1:edX+DemoX+DemoCourse+type@drag-and-drop-v2+block@dnd");
+
+    let structure: BTreeMap<_, _> = vec![(course.clone(), vec![html.clone(), lti.clone(), dnd.clone()])]
+        .into_iter()
+        .collect();
+    let app = |policy| {
+        stub_app(&user, &coursekey, structure.clone(), vec![completion(&user, &html, 1.0)])
+            .with_aggregation_config(AggregationConfig::default().with_unknown_blocktype_policy(policy))
+    };
+    let warnings = vec![
+        AggregationWarning::UnknownBlockType {
+            blocktype: "drag-and-drop-v2".to_owned(),
+        },
+        AggregationWarning::UnknownBlockType {
+            blocktype: "lti_consumer".to_owned(),
+        },
+    ];
+
+    let result = app(UnknownBlockTypePolicy::Completable)
+        .get_user_completion_with_warnings(&user, &coursekey)
+        .unwrap();
+    assert_eq!(result.aggregators[0].possible, 3.0);
+    assert_eq!(result.warnings, warnings);
+
+    let app_excluded = app(UnknownBlockTypePolicy::Excluded);
+    let result = app_excluded
+        .get_user_completion_with_warnings(&user, &coursekey)
+        .unwrap();
+    assert_eq!(result.aggregators[0].possible, 1.0);
+    assert_eq!(result.warnings, warnings);
+    let remaining = app_excluded.get_remaining_blocks(&user, &course).unwrap();
+    assert_eq!(
+        remaining
+            .excluded
+            .iter()
+            .map(|excluded| excluded.reason)
+            .collect::<Vec<_>>(),
+        vec![ExclusionReason::UnknownBlockType; 2]
+    );
+
+    match app(UnknownBlockTypePolicy::Error).get_user_completion(&user, &coursekey) {
+        Err(AppError::Structure(StructureError::UnknownBlockTypes(ref blocktypes)))
+            if blocktypes == &["drag-and-drop-v2", "lti_consumer"] => {}
+        other => panic!("Expected unknown blocktypes, got {:?}", other),
+    }
+    let report = app(UnknownBlockTypePolicy::Error)
+        .get_structure_report(&coursekey)
+        .unwrap();
+    assert_eq!(
+        report.error,
+        Some(StructureError::UnknownBlockTypes(vec![
+            "drag-and-drop-v2".to_owned(),
+            "lti_consumer".to_owned(),
+        ]))
+    );
+}
+
+#[test]
+fn test_optional_blocks() {
+    let user = User {
+        username: "test_user".to_owned(),
+    };
+    let root = key("block-v1:edX+DemoX+DemoCourse+type@course+block@course");
+    let chapter = key("block-v1:edX+DemoX+DemoCourse+type@chapter+block@chapter");
+    let bonus = key("block-v1:edX+DemoX+DemoCourse+type@chapter+block@bonus");
+    let html = key("block-v1:edX+DemoX+DemoCourse+type@html+block@html");
+    let poll = key("block-v1:edX+DemoX+DemoCourse+type@poll+block@poll");
+    let video = key("block-v1:edX+DemoX+DemoCourse+type@video+block@video");
+    let extra = key("block-v1:edX+DemoX+DemoCourse+type@html+block@extra");
+
+    let structure = vec![
+        (root.clone(), vec![chapter.clone(), bonus.clone(), extra.clone()]),
+        (chapter.clone(), vec![html.clone(), poll.clone()]),
+        (bonus.clone(), vec![video.clone()]),
+    ].into_iter()
+        .collect();
+    let config = AggregationConfig::default()
+        .with_block_mode(bonus.clone(), CompletionMode::Optional)
+        .with_block_mode(extra.clone(), CompletionMode::Optional);
+    let course = Course::from_structure(&structure, &config).unwrap();
+    let mut completions = completion_map(vec![
+        completion(&user, &html, 1.0),
+        completion(&user, &extra, 1.0),
+    ]);
+
+    let scores = |aggregators: Vec<Aggregator>| {
+        aggregators
+            .into_iter()
+            .map(|agg| (agg.block_key, agg.earned, agg.possible))
+            .collect::<Vec<_>>()
+    };
+    let previous = course.aggregate(&user, &completions);
+    assert_eq!(
+        scores(previous.clone()),
+        vec![
+            (chapter.clone(), 1.0, 2.0),
+            (bonus.clone(), 0.0, 1.0),
+            (root.clone(), 1.0, 2.0),
+        ]
+    );
+
+    let changed = completion(&user, &video, 1.0);
+    let updated = course.reaggregate(&previous, &changed, &completions);
+    assert_eq!(scores(updated), vec![(bonus.clone(), 1.0, 1.0)]);
+    completions.insert((user.clone(), video.clone()), changed);
+    assert_eq!(
+        scores(course.aggregate(&user, &completions)),
+        vec![
+            (chapter.clone(), 1.0, 2.0),
+            (bonus.clone(), 1.0, 1.0),
+            (root.clone(), 1.0, 2.0),
+        ]
+    );
+
+    let tree = course.aggregate_tree(&user, &completions, true);
+    assert_eq!(tree.children[2].block_key, extra);
+    assert_eq!(tree.children[2].mode, CompletionMode::Optional);
+    assert_eq!(tree.children[2].completion, Some(1.0));
+
+    let remaining = course.remaining(&user, &completions, &root).unwrap();
+    assert_eq!(
+        remaining.incomplete.iter().map(|block| &block.block_key).collect::<Vec<_>>(),
+        vec![&poll]
+    );
+    assert_eq!(
+        remaining
+            .excluded
+            .iter()
+            .map(|block| (&block.block_key, block.reason))
+            .collect::<Vec<_>>(),
+        vec![
+            (&bonus, ExclusionReason::Optional),
+            (&extra, ExclusionReason::Optional),
+        ]
+    );
+    completions.remove(&(user.clone(), video.clone()));
+    let remaining = course.remaining(&user, &completions, &bonus).unwrap();
+    assert_eq!(
+        remaining.incomplete.iter().map(|block| &block.block_key).collect::<Vec<_>>(),
+        vec![&video]
+    );
+}
+
+#[test]
+fn test_get_resume_block() {
+    let user = User {
+        username: "test_user".to_owned(),
+    };
+    let coursekey: CourseKey = "course-v1:edX+DemoX+DemoCourse".parse().unwrap();
+    let course = key("block-v1:edX+DemoX+DemoCourse+type@course+block@course");
+    let chapter1 = key("block-v1:edX+DemoX+DemoCourse+type@chapter+block@chapter1");
+    let chapter2 = key("block-v1:edX+DemoX+DemoCourse+type@chapter+block@chapter2");
+    let missing = key("block-v1:edX+DemoX+DemoCourse+type@chapter+block@missing");
+    let done = key("block-v1:edX+DemoX+DemoCourse+type@html+block@done");
+    let discussion = key("block-v1:edX+DemoX+DemoCourse+type@discussion+block@discussion");
+    let video = key("block-v1:edX+DemoX+DemoCourse+type@video+block@video");
+    let poll = key("block-v1:edX+DemoX+DemoCourse+type@poll+block@poll");
+
+    let app = stub_app(
+        &user,
+        &coursekey,
+        vec![
+            (course.clone(), vec![chapter1.clone(), chapter2.clone()]),
+            (chapter1.clone(), vec![done.clone()]),
+            (
+                chapter2.clone(),
+                vec![discussion.clone(), video.clone(), poll.clone()],
+            ),
+        ].into_iter()
+            .collect(),
+        vec![completion(&user, &done, 1.0)],
+    );
+    assert_eq!(app.get_resume_block(&user, &coursekey, None).unwrap(), Some(video));
+    assert_eq!(
+        app.get_resume_block(&user, &coursekey, Some(&chapter1)).unwrap(),
+        None
+    );
+    match app.get_resume_block(&user, &coursekey, Some(&missing)) {
+        Err(AppError::Structure(StructureError::RootNotFound(_))) => {}
+        result => panic!("Expected RootNotFound, got {:?}", result),
+    }
+}
+
+#[test]
+fn test_malformed_structures() {
+    fn check(structure: Vec<(UsageKey, Vec<UsageKey>)>) -> StructureError {
+        match Course::from_structure(&structure.into_iter().collect(), &AggregationConfig::default()) {
+            Ok(_) => panic!("Expected a StructureError"),
+            Err(err) => err,
+        }
+    }
+    let course = key("block-v1:edX+DemoX+DemoCourse+type@course+block@course");
+    let other_course = key("block-v1:edX+DemoX+Other+type@course+block@course");
+    let chapter = key("block-v1:edX+DemoX+DemoCourse+type@chapter+block@chapter");
+    let vertical = key("block-v1:edX+DemoX+DemoCourse+type@vertical+block@vertical");
+    let orphan = key("block-v1:edX+DemoX+DemoCourse+type@html+block@orphan");
+    let foreign = key("block-v1:edX+DemoX+Other+type@html+block@foreign");
+
+    assert_eq!(
+        check(vec![
+            (course.clone(), vec![chapter.clone()]),
+            (chapter.clone(), vec![vertical.clone()]),
+            (vertical.clone(), vec![chapter.clone()]),
+        ]),
+        StructureError::Cycle(vec![chapter.clone(), vertical.clone(), chapter.clone()])
+    );
+    assert_eq!(
+        check(vec![
+            (course.clone(), vec![chapter.clone()]),
+            (other_course.clone(), vec![]),
+        ]),
+        StructureError::MultipleCourseRoots(vec![course.clone(), other_course.clone()])
+    );
+    assert_eq!(
+        check(vec![(course.clone(), vec![chapter.clone()]), (chapter.clone(), vec![foreign.clone()])]),
+        StructureError::ForeignBlock {
+            parent: chapter.clone(),
+            child: foreign.clone(),
+        }
+    );
+    assert_eq!(
+        check(vec![(course.clone(), vec![chapter.clone()]), (vertical.clone(), vec![orphan.clone()])]),
+        StructureError::OrphanBlocks(vec![orphan.clone(), vertical.clone()])
+    );
+    assert_eq!(check(vec![(chapter.clone(), vec![])]), StructureError::NoCourseRoot);
+}
+
+#[test]
+fn test_structure_report() {
+    let course = key("block-v1:edX+DemoX+DemoCourse+type@course+block@course");
+    let chapter = key("block-v1:edX+DemoX+DemoCourse+type@chapter+block@chapter");
+    let discussions = key("block-v1:edX+DemoX+DemoCourse+type@chapter+block@discussions");
+    let vertical = key("block-v1:edX+DemoX+DemoCourse+type@vertical+block@vertical");
+    let html = key("block-v1:edX+DemoX+DemoCourse+type@html+block@html");
+    let discussion = key("block-v1:edX+DemoX+DemoCourse+type@discussion+block@discussion");
+    let custom = key("block-v1:edX+DemoX+DemoCourse+type@lti_consumer+block@custom");
+    let orphan = key("block-v1:edX+DemoX+DemoCourse+type@html+block@orphan");
+
+    let structure = vec![
+        (course.clone(), vec![chapter.clone(), discussions.clone()]),
+        (chapter.clone(), vec![vertical.clone()]),
+        (vertical.clone(), vec![html.clone(), custom.clone()]),
+        (discussions.clone(), vec![discussion.clone()]),
+        (orphan.clone(), vec![]),
+    ].into_iter()
+        .collect();
+    let report = validate_structure(&structure, &AggregationConfig::default());
+    assert_eq!(report.root, Some(course.clone()));
+    assert_eq!(report.error, Some(StructureError::OrphanBlocks(vec![orphan.clone()])));
+    assert_eq!(report.unreachable_blocks, vec![orphan.clone()]);
+    assert_eq!(
+        report.unknown_blocktypes.into_iter().collect::<Vec<_>>(),
+        vec!["lti_consumer".to_owned()]
+    );
+    assert_eq!(report.empty_aggregators, vec![discussions.clone()]);
+    assert_eq!(report.max_depth, 3);
+
+    let config = AggregationConfig::default()
+        .with_block_mode(discussion.clone(), CompletionMode::Completable)
+        .with_course_mode(course.course_key().clone(), "lti_consumer", CompletionMode::Excluded);
+    let report = validate_structure(&structure, &config);
+    assert!(report.empty_aggregators.is_empty());
+    assert!(report.unknown_blocktypes.is_empty());
+
+    // Children of an excluded block aren't aggregated, so their blocktypes
+    // don't need a mode.
+    let mut structure = structure;
+    structure.remove(&orphan);
+    for &vertical_mode in &[CompletionMode::Aggregator, CompletionMode::Excluded] {
+        let config = AggregationConfig::default().with_block_mode(vertical.clone(), vertical_mode);
+        let report = validate_structure(&structure, &config);
+        let course = Course::from_structure(&structure, &config).unwrap();
+        assert_eq!(report.unknown_blocktypes, course.unknown_blocktypes());
+        assert_eq!(report.unknown_blocktypes.is_empty(), vertical_mode == CompletionMode::Excluded);
+    }
+}
+
+#[test]
+fn test_xblock_mode_service() {
+    let user = User {
+        username: "test_user".to_owned(),
+    };
+    let coursekey: CourseKey = "course-v1:edX+DemoX+DemoCourse".parse().unwrap();
+    let course = key("block-v1:edX+DemoX+DemoCourse+type@course+block@course");
+    let html = key("block-v1:edX+DemoX+DemoCourse+type@html+block@html");
+    let lti = key("block-v1:edX+DemoX+DemoCourse+type@lti_consumer+block@lti");
+    let discussion = key("block-v1:edX+DemoX+DemoCourse+type@discussion+block@discussion");
+
+    let structure: BTreeMap<_, _> = vec![(course.clone(), vec![html.clone(), lti.clone(), discussion.clone()])]
+        .into_iter()
+        .collect();
+    let app = |modes| {
+        stub_app(&user, &coursekey, structure.clone(), vec![completion(&user, &html, 1.0)])
+            .with_xblock_mode_service(xblock::FileXBlockModeAdapter::new(modes))
+    };
+    let possible = |modes| {
+        app(modes)
+            .get_user_completion(&user, &coursekey)
+            .unwrap()
+            .iter()
+            .map(|agg| agg.possible)
+            .collect::<Vec<_>>()
+    };
+
+    let dir = std::env::temp_dir();
+    let json = dir.join("completion-test-xblock-modes.json");
+    std::fs::write(&json, r#"{"lti_consumer": "excluded"}"#).unwrap();
+    let toml = dir.join("completion-test-xblock-modes.toml");
+    std::fs::write(&toml, "lti_consumer = \"excluded\"\ndiscussion = \"completable\"\n").unwrap();
+    let missing = dir.join("completion-test-xblock-modes-missing.json");
+
+    assert_eq!(possible(json.clone()), vec![1.0]);
+    assert_eq!(possible(toml.clone()), vec![2.0]);
+    match app(missing.clone()).get_user_completion(&user, &coursekey) {
+        Err(AppError::XBlockModes(_)) => {}
+        other => panic!("Expected an xblock mode service error, got {:?}", other),
+    }
+
+    let modes = xblock::FileXBlockModeAdapter::new(&toml).get_xblock_modes().unwrap();
+    assert_eq!(modes["lti_consumer"], CompletionMode::Excluded);
+    assert_eq!(modes["discussion"], CompletionMode::Completable);
+    assert_eq!(modes["chapter"], CompletionMode::Aggregator);
+    assert_eq!(
+        xblock::BuiltinXBlockModeAdapter::new().get_xblock_modes().unwrap(),
+        get_xblock_modes()
+    );
+    let report = app(toml.clone()).get_structure_report(&coursekey).unwrap();
+    assert!(report.unknown_blocktypes.is_empty());
+    let config = AggregationConfig::default();
+    assert!(!validate_structure(&structure, &config).unknown_blocktypes.is_empty());
+    let validate = |modes| {
+        validate_structure_with_service(&structure, &config, &xblock::FileXBlockModeAdapter::new(modes))
+    };
+    assert!(validate(toml.clone()).unwrap().unknown_blocktypes.is_empty());
+    assert!(validate(missing.clone()).is_err());
+
+    std::fs::remove_file(&json).unwrap();
+    std::fs::remove_file(&toml).unwrap();
+}
+
+/// Serves the built-in xblock modes, counting how often they are loaded.
+struct CountingXBlockModeAdapter {
+    loads: Rc<Cell<usize>>,
+}
+
+impl XBlockModeService for CountingXBlockModeAdapter {
+    fn get_xblock_modes(&self) -> ports::Result<BTreeMap<String, CompletionMode>> {
+        self.loads.set(self.loads.get() + 1);
+        Ok(get_xblock_modes())
+    }
+}
+
+#[test]
+fn test_xblock_modes_loaded_once_per_call() {
+    let user = User {
+        username: "test_user".to_owned(),
+    };
+    let coursekey: CourseKey = "course-v1:edX+DemoX+DemoCourse".parse().unwrap();
+    let other: CourseKey = "course-v1:edX+DemoX+Other".parse().unwrap();
+    let course = key("block-v1:edX+DemoX+DemoCourse+type@course+block@course");
+    let html = key("block-v1:edX+DemoX+DemoCourse+type@html+block@html");
+
+    let app = App::new(
+        stubs::StubBlockCompletionAdapter::new(vec![]).with_users(vec![user.clone()]),
+        stubs::StubCourseAdapter::new(
+            coursekey.clone(),
+            vec![(course.clone(), vec![html.clone()])].into_iter().collect(),
+        ),
+        stubs::StubEnrollmentAdapter::new(vec![
+            (user.clone(), coursekey.clone()),
+            (user.clone(), other.clone()),
+        ]),
+    );
+    let loads = Rc::new(Cell::new(0));
+    let app = app.with_xblock_mode_service(CountingXBlockModeAdapter {
+        loads: loads.clone(),
+    });
+
+    app.get_enrolled_courses_completion(&user).unwrap();
+    assert_eq!(loads.get(), 1);
+    app.submit_completions_batch(&[completion(&user, &html, 1.0), completion(&user, &html, 0.5)])
+        .unwrap();
+    assert_eq!(loads.get(), 2);
+}
+
+#[test]
+fn test_submit_completion() {
+    let user = User {
+        username: "test_user".to_owned(),
+    };
+    let stranger = User {
+        username: "stranger".to_owned(),
+    };
+    let coursekey: CourseKey = "course-v1:edX+DemoX+DemoCourse".parse().unwrap();
+    let course = key("block-v1:edX+DemoX+DemoCourse+type@course+block@course");
+    let chapter = key("block-v1:edX+DemoX+DemoCourse+type@chapter+block@chapter");
+    let html = key("block-v1:edX+DemoX+DemoCourse+type@html+block@html");
+    let video = key("block-v1:edX+DemoX+DemoCourse+type@video+block@video");
+
+    let app = stub_app(
+        &user,
+        &coursekey,
+        vec![
+            (course.clone(), vec![chapter.clone()]),
+            (chapter.clone(), vec![html.clone(), video.clone()]),
+        ].into_iter()
+            .collect(),
+        vec![],
+    );
+    let earned = || {
+        app.get_user_completion(&user, &coursekey)
+            .unwrap()
+            .last()
+            .unwrap()
+            .earned
+    };
+
+    app.submit_completion(&completion(&user, &html, 0.5)).unwrap();
+    assert_eq!(earned(), 0.5);
+    app.submit_completion(&completion(&user, &html, 1.0)).unwrap();
+    assert_eq!(earned(), 1.0);
+
+    match app.submit_completions_batch(&[
+        completion(&user, &video, 1.0),
+        completion(&user, &html, 1.5),
+    ]) {
+        Err(AppError::InvalidCompletion(completion)) => assert_eq!(completion, 1.5),
+        other => panic!("Expected an invalid completion, got {:?}", other),
+    }
+    assert_eq!(earned(), 1.0);
+    match app.submit_completion(&completion(&user, &chapter, 1.0)) {
+        Err(AppError::BlockNotCompletable(ref block)) if block == &chapter => {}
+        other => panic!("Expected a block that isn't completable, got {:?}", other),
+    }
+    match app.submit_completion(&completion(&stranger, &video, 1.0)) {
+        Err(AppError::NotEnrolled) => {}
+        other => panic!("Expected NotEnrolled, got {:?}", other),
+    }
+
+    app.submit_completions_batch(&[completion(&user, &video, 1.0)]).unwrap();
+    assert_eq!(earned(), 2.0);
+
+    // Without an enrollment check, a user the backend doesn't know is still
+    // rejected, and nothing in the batch is recorded.
+    let app = app.with_enrollment_policy(EnrollmentPolicy::Skip);
+    match app.submit_completions_batch(&[
+        completion(&user, &video, 0.0),
+        completion(&stranger, &video, 1.0),
+    ]) {
+        Err(AppError::BlockCompletion(ports::ServiceError::NotFound)) => {}
+        other => panic!("Expected NotFound, got {:?}", other),
+    }
+    assert_eq!(
+        app.get_user_completion(&user, &coursekey).unwrap().last().unwrap().earned,
+        2.0
+    );
+}
+
 #[test]
 fn test_db_adapter() {
     // This test needs a configured connection to an edxapp DB.  You will need